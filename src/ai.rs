@@ -1,63 +1,264 @@
-use serde::{Deserialize, Serialize};
-use crate::{Error, Result, AiConfig, is_conventional_commit};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crate::{
+    Error, Result, AiConfig, ClassificationThresholds, CommitConfig, HuggingFaceProvider, MessageLength, PromptStyle,
+    Provider, is_conventional_commit_with_types,
+};
+use crate::conventional::parse_conventional;
+use crate::protocol::together_url;
+use crate::recording::Exchange;
+use crate::diff::{GENERATED_MARKER, GENERIC_DIRS, count_tokens, strip_comment_changes, truncate_to_tokens};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use glob::Pattern;
 use reqwest::StatusCode;
-use std::{time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+use tokio_util::sync::CancellationToken;
 
-const API_URL: &str = "https://api.together.xyz/v1/chat/completions";
 const MAX_RETRIES: u32 = 3;
 const INITIAL_RETRY_DELAY_MS: u64 = 1000;
+/// Token cap for the project context supplied with `with_project_context`
+pub const MAX_PROJECT_CONTEXT_TOKENS: usize = 1000;
+/// Cap on the module names supplied with `with_tree_context`
+pub const MAX_TREE_ENTRIES: usize = 60;
+/// Response budget of the one-sentence summaries of `summarize_file_diff`
+const SUMMARY_MAX_TOKENS: u32 = 80;
 
-#[derive(Debug)]
-struct CommitContext {
-    commit_type: String,
-    file_types: Vec<String>,
-    new_files: Vec<String>,
-    modified_files: Vec<String>,
-    total_additions: usize,
-    total_deletions: usize,
+/// Heuristic classification of a diff, used to steer the prompt
+#[derive(Debug, Serialize)]
+pub struct CommitContext {
+    pub commit_type: String,
+    pub file_types: Vec<String>,
+    pub new_files: Vec<String>,
+    pub modified_files: Vec<String>,
+    pub deleted_files: Vec<String>,
+    /// Generated or vendored files, whose changes are left out of the diff
+    pub generated_files: Vec<String>,
+    pub total_additions: usize,
+    pub total_deletions: usize,
+    /// Added lines that don't re-add a line removed from the same file, so moved or
+    /// churned lines don't count as new code
+    pub net_additions: usize,
+    /// Removed lines that aren't added back to the same file
+    pub net_deletions: usize,
+    pub issue_refs: Vec<(String, u32)>,
+    /// Weight of security keywords in added lines, see `is_security_related`
+    pub security_signals: usize,
+    /// Weight of each module of the crate in changed Rust lines, see `rust_module_hints`
+    pub rust_modules: BTreeMap<String, usize>,
+}
+
+/// Number of added lines that equal a removed line, each removed line matching once
+fn matching_lines(added: &[&str], removed: &[&str]) -> usize {
+    let mut removed_counts: HashMap<&str, usize> = HashMap::new();
+    for line in removed {
+        *removed_counts.entry(line).or_default() += 1;
+    }
+    added
+        .iter()
+        .filter(|line| match removed_counts.get_mut(*line) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                true
+            }
+            _ => false,
+        })
+        .count()
+}
+
+/// Weight from which a module touched by changed Rust lines is suggested as the scope
+const MIN_MODULE_WEIGHT: usize = 3;
+
+/// Modules of the crate a changed Rust line points at: a `mod foo;` declaration weighs
+/// `MIN_MODULE_WEIGHT` on its own, each `crate::foo::`, `self::foo::` or `super::foo::`
+/// path weighs 1
+fn rust_module_hints(line: &str) -> Vec<(String, usize)> {
+    let line = line.trim();
+    let declaration = line
+        .strip_prefix("pub ")
+        .or_else(|| line.strip_prefix("pub(crate) "))
+        .unwrap_or(line)
+        .strip_prefix("mod ")
+        .and_then(|rest| rest.strip_suffix(';'));
+    if let Some(name) = declaration {
+        return vec![(name.trim().to_string(), MIN_MODULE_WEIGHT)];
+    }
+
+    line.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':'))
+        .filter_map(|path| {
+            let mut segments = path.split("::");
+            matches!(segments.next(), Some("crate" | "self" | "super")).then_some(())?;
+            let module = segments.find(|segment| !matches!(*segment, "self" | "super"))?;
+            // `crate::Error` names an item rather than a module
+            let is_module = segments.next().is_some()
+                && module.starts_with(|c: char| c.is_ascii_lowercase() || c == '_');
+            is_module.then(|| (module.to_string(), 1))
+        })
+        .collect()
+}
+
+/// Share of changed lines from deleted files above which a diff counts as a file removal
+const FILE_REMOVAL_SHARE: f32 = 0.8;
+
+/// Tells the user why and for how long the client waits before `attempt`,
+/// e.g. `Rate limited, retrying in 2s (attempt 2/3)`
+fn retry_note(status: StatusCode, delay: Duration, attempt: u32) -> String {
+    let reason = if status == StatusCode::TOO_MANY_REQUESTS {
+        "Rate limited"
+    } else {
+        "Service unavailable"
+    };
+    format!("{}, retrying in {}s (attempt {}/{})", reason, delay.as_secs_f32(), attempt, MAX_RETRIES)
+}
+
+/// Signal weight from which a diff counts as security-related
+const MIN_SECURITY_SIGNALS: usize = 3;
+
+/// Security signal of an added line: a CVE or vulnerability mention is enough on its own,
+/// sanitizing, escaping and authentication code only adds up over several lines
+fn security_signal(line: &str) -> usize {
+    let line = line.to_lowercase();
+    if line.contains("cve-") || line.contains("vulnerab") {
+        return MIN_SECURITY_SIGNALS;
+    }
+    let is_keyword = |word: &str| {
+        word.starts_with("sanitiz")
+            || word.starts_with("escape")
+            || (word.starts_with("auth") && !word.starts_with("author"))
+    };
+    usize::from(line.split(|c: char| !c.is_alphanumeric()).any(is_keyword))
 }
 
 impl CommitContext {
-    fn from_diff(diff: &str) -> Self {
+    /// Classifies a diff with the default thresholds
+    pub fn from_diff(diff: &str) -> Self {
+        Self::from_diff_with_thresholds(diff, &ClassificationThresholds::default())
+    }
+
+    pub fn from_diff_with_thresholds(diff: &str, thresholds: &ClassificationThresholds) -> Self {
         let mut context = CommitContext {
             commit_type: String::new(),
             file_types: Vec::new(),
             new_files: Vec::new(),
             modified_files: Vec::new(),
+            deleted_files: Vec::new(),
+            generated_files: Vec::new(),
             total_additions: 0,
             total_deletions: 0,
+            net_additions: 0,
+            net_deletions: 0,
+            issue_refs: Vec::new(),
+            security_signals: 0,
+            rust_modules: BTreeMap::new(),
         };
+        // Added and removed lines per file, to cancel out lines that were re-added
+        let mut churn: Vec<(Vec<&str>, Vec<&str>)> = Vec::new();
 
+        // Added and removed content per file with all whitespace dropped, to spot
+        // formatting-only changes
+        let mut reflowed: Vec<(String, String)> = Vec::new();
         let mut current_file = String::new();
+        // Lines removed along with their whole file
+        let mut removed_file_lines = 0;
+        let mut all_ci_config = true;
+        // `Config::validate` rejects invalid globs, but it doesn't run on every path
+        let ci_paths: Vec<Pattern> = thresholds
+            .ci_paths
+            .iter()
+            .filter_map(|pattern| {
+                Pattern::new(pattern)
+                    .inspect_err(|e| warn!("Ignoring invalid ci_paths glob '{}': {}", pattern, e))
+                    .ok()
+            })
+            .collect();
         for line in diff.lines() {
             if line.starts_with("diff --git") {
-                current_file = line.split(' ').last().unwrap_or("").trim_start_matches('b').to_string();
-                if let Some(ext) = current_file.split('.').last() {
+                reflowed.push((String::new(), String::new()));
+                churn.push((Vec::new(), Vec::new()));
+                let path = line.split(' ').next_back().unwrap_or("");
+                current_file = path.strip_prefix("b/").unwrap_or(path).to_string();
+                all_ci_config &= ci_paths.iter().any(|pattern| pattern.matches(&current_file));
+                if let Some(ext) = current_file.split('.').next_back() {
                     context.file_types.push(ext.to_string());
                 }
+                // Assume a modification until a new/deleted file header says otherwise
+                context.modified_files.push(current_file.clone());
             } else if line.starts_with("new file") {
+                context.modified_files.retain(|f| *f != current_file);
                 context.new_files.push(current_file.clone());
-            } else if line.starts_with("modified") {
-                context.modified_files.push(current_file.clone());
+            } else if line.starts_with("deleted file") {
+                context.modified_files.retain(|f| *f != current_file);
+                context.deleted_files.push(current_file.clone());
+            } else if line == GENERATED_MARKER {
+                context.generated_files.push(current_file.clone());
             } else if line.starts_with('+') && !line.starts_with("+++") {
                 context.total_additions += 1;
+                if current_file.ends_with(".rs") {
+                    context.add_rust_module_hints(&line[1..]);
+                }
+                context.security_signals += security_signal(&line[1..]);
+                if let Some((added, _)) = reflowed.last_mut() {
+                    added.extend(line[1..].chars().filter(|c| !c.is_whitespace()));
+                }
+                if let Some((added, _)) = churn.last_mut() {
+                    added.push(line[1..].trim());
+                }
+                for (keyword, issue) in parse_issue_keywords(&line[1..]) {
+                    if !context.issue_refs.iter().any(|(_, n)| *n == issue) {
+                        context.issue_refs.push((keyword, issue));
+                    }
+                }
             } else if line.starts_with('-') && !line.starts_with("---") {
                 context.total_deletions += 1;
+                if current_file.ends_with(".rs") {
+                    context.add_rust_module_hints(&line[1..]);
+                }
+                if context.deleted_files.last() == Some(&current_file) {
+                    removed_file_lines += 1;
+                }
+                if let Some((_, removed)) = reflowed.last_mut() {
+                    removed.extend(line[1..].chars().filter(|c| !c.is_whitespace()));
+                }
+                if let Some((_, removed)) = churn.last_mut() {
+                    removed.push(line[1..].trim());
+                }
             }
         }
+        let matched: usize = churn.iter().map(|(added, removed)| matching_lines(added, removed)).sum();
+        context.net_additions = context.total_additions - matched;
+        context.net_deletions = context.total_deletions - matched;
+        let style_only = context.changed_lines() > 0
+            && reflowed.iter().all(|(added, removed)| added == removed);
 
         // Determine commit type based on context
-        context.commit_type = if context.new_files.iter().any(|f| f.contains("Cargo.toml")) 
-            && context.new_files.len() > 5 {
+        let generated_only = !context.generated_files.is_empty()
+            && context.generated_files.len() == context.files_changed();
+        context.commit_type = if generated_only {
+            "generated or vendored update".to_string()
+        } else if all_ci_config && context.files_changed() > 0 {
+            "ci configuration change".to_string()
+        } else if style_only {
+            "style change".to_string()
+        } else if context.new_files.iter().any(|f| f.contains("Cargo.toml")) 
+            && context.new_files.len() > thresholds.many_files_threshold {
             "initial project setup".to_string()
         } else if context.file_types.iter().any(|t| t == "md" || t == "txt") 
             && context.file_types.len() == 1 {
             "documentation change".to_string()
+        } else if !context.deleted_files.is_empty()
+            && removed_file_lines as f32 >= context.changed_lines() as f32 * FILE_REMOVAL_SHARE {
+            "file removal".to_string()
         } else if context.new_files.iter().any(|f| f.contains("test") || f.contains("spec")) {
             "test addition".to_string()
-        } else if context.total_additions > 100 || context.new_files.len() > 5 {
+        } else if context.net_additions > thresholds.large_additions_threshold
+            || context.new_files.len() > thresholds.many_files_threshold {
             "large feature implementation".to_string()
-        } else if context.total_deletions > context.total_additions * 2 {
+        } else if context.net_deletions as f32
+            > context.net_additions as f32 * thresholds.refactor_deletion_ratio {
             "major refactoring".to_string()
         } else {
             "standard change".to_string()
@@ -66,19 +267,163 @@ impl CommitContext {
         context
     }
 
-    fn get_suggested_type(&self) -> &'static str {
+    pub fn get_suggested_type(&self) -> &'static str {
         match self.commit_type.as_str() {
             "initial project setup" => "feat",
             "documentation change" => "docs",
             "test addition" => "test",
             "large feature implementation" => "feat",
             "major refactoring" => "refactor",
+            "style change" => "style",
+            "generated or vendored update" => "chore",
+            "file removal" => "chore",
+            "ci configuration change" => "ci",
             _ => "feat"
         }
     }
 
-    fn to_prompt_context(&self) -> String {
+    /// Infers a scope from the deepest directory shared by all new and modified files,
+    /// skipping generic directory names like `src`
+    pub fn infer_scope(&self) -> Option<String> {
+        let mut dirs = self.new_files.iter().chain(&self.modified_files).map(|file| {
+            let mut parts: Vec<&str> = file.split('/').collect();
+            parts.pop();
+            parts
+        });
+        let mut common = dirs.next()?;
+        for dir in dirs {
+            let shared = common.iter().zip(&dir).take_while(|(a, b)| a == b).count();
+            common.truncate(shared);
+        }
+
+        common
+            .into_iter()
+            .rev()
+            .find(|dir| !GENERIC_DIRS.contains(dir))
+            .map(str::to_string)
+    }
+
+    fn add_rust_module_hints(&mut self, line: &str) {
+        for (module, weight) in rust_module_hints(line) {
+            *self.rust_modules.entry(module).or_default() += weight;
+        }
+    }
+
+    /// Infers a scope from the crate module that changed `mod` declarations and
+    /// `crate::` paths point at, when Rust files make up most of the diff and one
+    /// module outweighs all others together
+    pub fn infer_rust_scope(&self) -> Option<String> {
+        let rust_files = self.file_types.iter().filter(|ext| *ext == "rs").count();
+        if rust_files * 2 <= self.file_types.len() {
+            return None;
+        }
+        let total: usize = self.rust_modules.values().sum();
+        let (module, weight) = self.rust_modules.iter().max_by_key(|(_, weight)| **weight)?;
+        (*weight >= MIN_MODULE_WEIGHT && *weight * 2 > total).then(|| module.clone())
+    }
+
+    /// Infers a scope from the Cargo workspace member owning all the given files, found by
+    /// the nearest `Cargo.toml` with a package name. Files owned by the root manifest, or
+    /// by different crates, give no scope.
+    pub fn infer_cargo_scope(repo_root: &Path, files: &[String]) -> Option<String> {
+        let mut crates = files.iter().map(|file| owning_crate(repo_root, Path::new(file)));
+        let first = crates.next()??;
+        crates.all(|name| name.as_ref() == Some(&first)).then_some(first)
+    }
+
+    /// Whether added lines mention vulnerabilities, or sanitizing, escaping or
+    /// authentication often enough to suggest a `security` scope
+    pub fn is_security_related(&self) -> bool {
+        self.security_signals >= MIN_SECURITY_SIGNALS
+    }
+
+    /// Number of added and removed lines
+    pub fn changed_lines(&self) -> usize {
+        self.total_additions + self.total_deletions
+    }
+
+    /// A simple message for trivial changes that aren't worth an API call
+    pub fn local_message(&self) -> String {
+        let commit_type = match self.get_suggested_type() {
+            commit_type @ ("docs" | "style") => commit_type,
+            _ => "chore",
+        };
+        let files: Vec<&String> = self.new_files.iter().chain(&self.modified_files).collect();
+        match files.as_slice() {
+            [file] => {
+                let name = file.rsplit('/').next().unwrap_or(file);
+                format!("{}: minor edits to {}", commit_type, name)
+            }
+            files => format!("{}: minor edits to {} files", commit_type, files.len()),
+        }
+    }
+
+    /// A best-effort message from the classification alone, for when the API can't be used,
+    /// e.g. `feat(auth): update 3 files`
+    pub fn heuristic_message(&self) -> String {
+        let files = self.files_changed();
+        let scope = self
+            .infer_rust_scope()
+            .or_else(|| self.infer_scope())
+            .map(|scope| format!("({})", scope))
+            .unwrap_or_default();
+        if self.commit_type == "file removal" {
+            return format!("chore{}: remove {}", scope, self.removed_components());
+        }
+        format!(
+            "{}{}: update {} file{}",
+            self.get_suggested_type(),
+            scope,
+            files,
+            if files == 1 { "" } else { "s" }
+        )
+    }
+
+    /// Names of the deleted files without directories and extensions, e.g. `legacy and utils`
+    fn removed_components(&self) -> String {
+        let mut names: Vec<&str> = self
+            .deleted_files
+            .iter()
+            .map(|file| {
+                let name = file.rsplit('/').next().unwrap_or(file);
+                name.split_once('.').map_or(name, |(stem, _)| stem)
+            })
+            .collect();
+        names.dedup();
+        match names.split_last() {
+            Some((last, [])) => last.to_string(),
+            Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+            None => String::new(),
+        }
+    }
+
+    /// Serializes the classification, including the suggested commit type
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        value["suggested_type"] = self.get_suggested_type().into();
+        value
+    }
+
+    /// Number of files in the diff, including deleted ones
+    pub fn files_changed(&self) -> usize {
+        // `file_types` gets one entry per file header
+        self.file_types.len()
+    }
+
+    /// One-line overview, e.g. `12 files changed, +340/-50, suggested type: feat`
+    pub fn summary(&self) -> String {
         format!(
+            "{} file{} changed, +{}/-{}, suggested type: {}",
+            self.files_changed(),
+            if self.files_changed() == 1 { "" } else { "s" },
+            self.total_additions,
+            self.total_deletions,
+            self.get_suggested_type()
+        )
+    }
+
+    fn to_prompt_context(&self) -> String {
+        let mut context = format!(
             "{} (suggested type: {}) with {} new files and {} modified files. \
             Changes include {} additions and {} deletions across file types: {}",
             self.commit_type,
@@ -88,8 +433,81 @@ impl CommitContext {
             self.total_additions,
             self.total_deletions,
             self.file_types.join(", ")
-        )
+        );
+        if (self.net_additions, self.net_deletions) != (self.total_additions, self.total_deletions) {
+            context.push_str(&format!(
+                " (net {} additions and {} deletions, not counting moved or re-added lines)",
+                self.net_additions, self.net_deletions
+            ));
+        }
+        if !self.deleted_files.is_empty() {
+            context.push_str(&format!(
+                ". Deleted files, name the removed components in the description: {}",
+                self.deleted_files.join(", ")
+            ));
+        }
+        context
+    }
+}
+
+/// Package name from the nearest `Cargo.toml` above a repository-relative file, unless that
+/// manifest is the one at the repository root
+fn owning_crate(repo_root: &Path, file: &Path) -> Option<String> {
+    let mut dir = file.parent();
+    while let Some(current) = dir.filter(|d| !d.as_os_str().is_empty()) {
+        let manifest = repo_root.join(current).join("Cargo.toml");
+        if manifest.is_file() {
+            let manifest: toml::Value = toml::from_str(&std::fs::read_to_string(manifest).ok()?).ok()?;
+            return manifest.get("package")?.get("name")?.as_str().map(String::from);
+        }
+        dir = current.parent();
     }
+    None
+}
+
+/// Validates and converts user-supplied headers into a `HeaderMap`
+pub(crate) fn build_headers(headers: &HashMap<String, String>) -> Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| Error::InvalidHeader(format!("invalid header name '{}'", name)))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| Error::InvalidHeader(format!("invalid value for header '{}'", name)))?;
+        map.insert(name, value);
+    }
+    Ok(map)
+}
+
+/// Finds `closes|fixes|resolves #N` references in a line, case-insensitively
+fn parse_issue_keywords(line: &str) -> Vec<(String, u32)> {
+    const KEYWORDS: [&str; 3] = ["Closes", "Fixes", "Resolves"];
+
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let mut refs = Vec::new();
+    for pair in words.windows(2) {
+        let Some(keyword) = KEYWORDS.iter().find(|k| {
+            pair[0].trim_end_matches(':').eq_ignore_ascii_case(k)
+        }) else {
+            continue;
+        };
+        let number = pair[1]
+            .strip_prefix('#')
+            .map(|n| n.trim_end_matches(|c: char| !c.is_ascii_digit()))
+            .and_then(|n| n.parse::<u32>().ok());
+        if let Some(number) = number {
+            refs.push((keyword.to_string(), number));
+        }
+    }
+    refs
+}
+
+/// Builds `Closes #N`-style footers from issue keywords in the added lines of a diff
+pub fn issue_footers(diff: &str) -> Vec<String> {
+    CommitContext::from_diff(diff)
+        .issue_refs
+        .into_iter()
+        .map(|(keyword, issue)| format!("{} #{}", keyword, issue))
+        .collect()
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -105,6 +523,98 @@ struct ChatRequest {
     temperature: f32,
     max_tokens: u32,
     stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ResponseFormat>,
+}
+
+/// OpenAI-style `response_format`, used to enable JSON mode
+#[derive(Debug, Serialize, Clone)]
+struct ResponseFormat {
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
+
+const STRUCTURED_INSTRUCTION: &str = "Respond only with a JSON object with the keys \
+    \"type\" (the commit type), \"scope\" (the scope, or null for none), \"description\" \
+    (the subject description) and \"body\" (bullet-point body, or null for none).";
+
+/// Commit message fields requested in JSON mode
+#[derive(Debug, Deserialize)]
+struct StructuredMessage {
+    #[serde(rename = "type")]
+    commit_type: String,
+    #[serde(default)]
+    scope: Option<String>,
+    description: String,
+    #[serde(default)]
+    body: Option<String>,
+}
+
+impl StructuredMessage {
+    /// Assembles the conventional commit message from the parsed fields
+    fn to_message(&self) -> String {
+        let commit_type = self.commit_type.trim().to_lowercase();
+        let description = self.description.trim().trim_end_matches('.');
+        let mut message = match self.scope.as_deref().map(str::trim) {
+            Some(scope) if !scope.is_empty() => format!("{}({}): {}", commit_type, scope, description),
+            _ => format!("{}: {}", commit_type, description),
+        };
+        if let Some(body) = self.body.as_deref().map(str::trim).filter(|b| !b.is_empty()) {
+            message.push_str("\n\n");
+            message.push_str(body);
+        }
+        message
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CompletionRequest<'a> {
+    model: &'a str,
+    prompt: String,
+    temperature: f32,
+    max_tokens: u32,
+    stop: &'a [String],
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionResponse {
+    choices: Vec<CompletionChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionChoice {
+    text: String,
+}
+
+/// Flattens chat messages into a single prompt for completion-style models
+fn format_prompt(style: PromptStyle, messages: &[ChatMessage]) -> String {
+    let content = |role: &str| {
+        messages
+            .iter()
+            .filter(|m| m.role == role)
+            .map(|m| m.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    };
+    let (system, user) = (content("system"), content("user"));
+
+    match style {
+        PromptStyle::Chat | PromptStyle::MistralInstruct => {
+            format!("<s>[INST] {}\n\n{} [/INST]", system, user)
+        }
+        PromptStyle::Llama2 => {
+            format!("<s>[INST] <<SYS>>\n{}\n<</SYS>>\n\n{} [/INST]", system, user)
+        }
+    }
+}
+
+/// A generated commit message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generated {
+    pub message: String,
+    /// Regenerations because of the message's format or type, at most
+    /// `AiConfig::max_validation_retries`
+    pub validation_retries: u32,
 }
 
 #[derive(Debug, Deserialize)]
@@ -122,10 +632,35 @@ struct ChatResponseMessage {
     content: String,
 }
 
+#[derive(Clone)]
 pub struct AiClient {
     client: reqwest::Client,
     api_key: String,
     config: AiConfig,
+    previous_diff: Option<String>,
+    project_context: Option<String>,
+    /// Module and directory names of the repository, offered as scopes
+    tree_context: Vec<String>,
+    /// Every changed file with its status, listed below the (possibly truncated) diff
+    file_list: Vec<(&'static str, String)>,
+    /// File the diff is written to as sent, after comment stripping and truncation
+    sent_diff_path: Option<PathBuf>,
+    explain_why: bool,
+    reason: Option<String>,
+    allowed_types: Vec<String>,
+    trust_model_type: bool,
+    gitmoji: bool,
+    trace_api: bool,
+    retry_notes: bool,
+    repo_root: Option<PathBuf>,
+    /// Set once the provider rejects JSON mode, so later requests go straight to plain mode.
+    /// Shared with clones, but not with clients made by `for_model`.
+    json_mode_rejected: Arc<AtomicBool>,
+    /// File every API exchange is written to, with the exchanges so far. Shared with
+    /// clients made by `for_model`.
+    record: Option<Arc<(PathBuf, Mutex<Vec<Exchange>>)>>,
+    /// Recorded exchanges not yet replayed, answering requests instead of the API
+    replay: Option<Arc<Mutex<Vec<Exchange>>>>,
 }
 
 impl AiClient {
@@ -134,100 +669,1325 @@ impl AiClient {
             client: reqwest::Client::new(),
             api_key,
             config,
+            previous_diff: None,
+            project_context: None,
+            tree_context: Vec::new(),
+            file_list: Vec::new(),
+            sent_diff_path: None,
+            explain_why: false,
+            reason: None,
+            allowed_types: CommitConfig::default().allowed_types,
+            trust_model_type: true,
+            gitmoji: false,
+            trace_api: false,
+            retry_notes: false,
+            repo_root: None,
+            json_mode_rejected: Arc::new(AtomicBool::new(false)),
+            record: None,
+            replay: None,
         }
     }
 
+    /// Writes the exact body and response of every API call to `path`, for audits or to
+    /// replay the run later with `with_replay`
+    pub fn with_recording(mut self, path: PathBuf) -> Self {
+        self.record = Some(Arc::new((path, Mutex::new(Vec::new()))));
+        self
+    }
+
+    /// Answers requests with recorded responses without calling the API. Each request is
+    /// answered by the first unused exchange with the same URL and body, so concurrent
+    /// requests get their own responses whatever order they are sent in.
+    pub fn with_replay(mut self, exchanges: Vec<Exchange>) -> Self {
+        self.replay = Some(Arc::new(Mutex::new(exchanges)));
+        self
+    }
+
+    /// A client with the same settings for another model, sharing any recording or replay,
+    /// e.g. to compare models concurrently
+    pub fn for_model(&self, model: String) -> Self {
+        let mut client = self.clone();
+        client.config.model = model;
+        // JSON mode support differs between models
+        client.json_mode_rejected = Arc::new(AtomicBool::new(false));
+        client
+    }
+
+    /// Restricts generated messages to the given commit types
+    pub fn with_allowed_types(mut self, allowed_types: Vec<String>) -> Self {
+        self.allowed_types = allowed_types;
+        self
+    }
+
+    /// Whether to accept any valid message (the default), or to retry once when its type
+    /// differs from the suggested one. The suggestion is part of the prompt either way.
+    pub fn with_trust_model_type(mut self, trust_model_type: bool) -> Self {
+        self.trust_model_type = trust_model_type;
+        self
+    }
+
+    /// Whether to keep a leading gitmoji in messages, see `CommitConfig::gitmoji`
+    pub fn with_gitmoji(mut self, gitmoji: bool) -> Self {
+        self.gitmoji = gitmoji;
+        self
+    }
+
+    /// Includes the previous commit's diff as context, so fixes for a regression it
+    /// introduced can reference it. It is truncated first when over the token budget.
+    pub fn with_previous_diff(mut self, diff: String) -> Self {
+        self.previous_diff = Some(diff);
+        self
+    }
+
+    /// Adds project-specific guidance, such as domain terms and scope conventions, as an
+    /// extra system message. It is capped at `MAX_PROJECT_CONTEXT_TOKENS`.
+    pub fn with_project_context(mut self, context: String) -> Self {
+        let tokens = count_tokens(&context);
+        if tokens > MAX_PROJECT_CONTEXT_TOKENS {
+            warn!(
+                "Project context is {} tokens, truncating it to {}",
+                tokens, MAX_PROJECT_CONTEXT_TOKENS
+            );
+        }
+        let context = truncate_to_tokens(&context, MAX_PROJECT_CONTEXT_TOKENS);
+        if count_tokens(&context) > self.config.max_diff_tokens / 4 {
+            warn!("Project context is large compared to the diff budget and may crowd out the diff");
+        }
+        self.project_context = Some(context);
+        self
+    }
+
+    /// Lists the repository's modules and directories (see `GitRepo::module_names`) in the
+    /// system context, so the model picks scopes that exist. At most `MAX_TREE_ENTRIES`
+    /// are kept.
+    pub fn with_tree_context(mut self, mut names: Vec<String>) -> Self {
+        if names.len() > MAX_TREE_ENTRIES {
+            warn!("Repository has {} modules and directories, listing the first {}", names.len(), MAX_TREE_ENTRIES);
+            names.truncate(MAX_TREE_ENTRIES);
+        }
+        self.tree_context = names;
+        self
+    }
+
+    /// Lists every changed file with its status (see `GitRepo::changed_files`) in the prompt,
+    /// so the model sees the breadth of a change whose diff was truncated
+    pub fn with_file_list(mut self, files: Vec<(&'static str, String)>) -> Self {
+        self.file_list = files;
+        self
+    }
+
+    /// Writes the diff exactly as it goes into the prompt, after comment stripping and
+    /// truncation, to `path`. Each generation overwrites it.
+    pub fn with_sent_diff_dump(mut self, path: PathBuf) -> Self {
+        self.sent_diff_path = Some(path);
+        self
+    }
+
+    /// Asks for the body to say why the change was made. The author's `reason` is woven in
+    /// when given; otherwise only a rationale evident from the diff is allowed. Has no
+    /// effect unless a body is requested.
+    pub fn with_explain_why(mut self, reason: Option<String>) -> Self {
+        self.explain_why = true;
+        self.reason = reason.filter(|r| !r.trim().is_empty());
+        self
+    }
+
+    /// Repository work tree, used to map changed files to their Cargo workspace member
+    pub fn with_repo_root(mut self, repo_root: PathBuf) -> Self {
+        self.repo_root = Some(repo_root);
+        self
+    }
+
+    /// Logs the full JSON of every API request and response at debug level, with the
+    /// API key and credential headers masked
+    pub fn with_api_trace(mut self, trace_api: bool) -> Self {
+        self.trace_api = trace_api;
+        self
+    }
+
+    /// Prints a note to stderr while waiting to retry a rate-limited or unavailable API,
+    /// so the backoff doesn't look like a hang
+    pub fn with_retry_notes(mut self, retry_notes: bool) -> Self {
+        self.retry_notes = retry_notes;
+        self
+    }
+
     pub async fn generate_commit_message(&self, diff: &str) -> Result<String> {
-        let context = CommitContext::from_diff(diff);
-        
-        let request = ChatRequest {
+        self.generate_with_instruction(diff, None).await
+    }
+
+    /// Like `generate_commit_message`, but aborts the in-flight request and returns
+    /// `Error::Cancelled` as soon as `token` is cancelled
+    pub async fn generate_commit_message_cancellable(
+        &self,
+        diff: &str,
+        token: &CancellationToken,
+    ) -> Result<String> {
+        // Checking the token first means an already cancelled token never starts a request
+        tokio::select! {
+            biased;
+            _ = token.cancelled() => Err(Error::Cancelled),
+            result = self.generate_commit_message(diff) => result,
+        }
+    }
+
+    /// Generates a commit message with an extra instruction appended to the user prompt,
+    /// e.g. to correct a previous attempt
+    pub async fn generate_commit_message_with_instruction(
+        &self,
+        diff: &str,
+        instruction: &str,
+    ) -> Result<String> {
+        self.generate_with_instruction(diff, Some(instruction)).await
+    }
+
+    /// Summarizes the diff of one file in a sentence, for `AiConfig::hierarchical_summary`.
+    /// The file's diff is truncated to `max_diff_tokens` first.
+    pub async fn summarize_file_diff(&self, path: &str, diff: &str) -> Result<String> {
+        let request = self.summary_request(path, diff);
+        if self.config.prompt_style != PromptStyle::Chat {
+            return self.try_generate_completion(&request).await;
+        }
+        let response = self
+            .send::<_, ChatResponse>(&self.endpoint(false), &request, request.max_tokens)
+            .await?;
+        response
+            .choices
+            .first()
+            .map(|choice| choice.message.content.trim().to_string())
+            .ok_or_else(|| Error::CommitMessageGeneration("No response from API".to_string()))
+    }
+
+    fn summary_request(&self, path: &str, diff: &str) -> ChatRequest {
+        ChatRequest {
             model: self.config.model.clone(),
             messages: vec![
                 ChatMessage {
                     role: "system".to_string(),
-                    content: self.config.system_prompt.clone(),
+                    content: "You summarize code changes for a commit message writer.".to_string(),
                 },
                 ChatMessage {
                     role: "user".to_string(),
-                    content: self.config.user_prompt_template
-                        .replace("{}", &context.to_prompt_context())
-                        .replace("{}", diff),
+                    content: format!(
+                        "Summarize what the following diff of {} changes in one sentence. \
+                        Only return the sentence.\n\n{}",
+                        path,
+                        truncate_to_tokens(diff, self.config.max_diff_tokens)
+                    ),
                 },
             ],
             temperature: self.config.temperature,
-            max_tokens: self.config.max_tokens,
-            stop: self.config.stop_sequences.clone(),
-        };
+            max_tokens: SUMMARY_MAX_TOKENS,
+            stop: Vec::new(),
+            response_format: None,
+        }
+    }
 
-        let mut last_error = None;
-        for retry in 0..MAX_RETRIES {
-            if retry > 0 {
-                tokio::time::sleep(Duration::from_millis(
-                    INITIAL_RETRY_DELAY_MS * (2_u64.pow(retry - 1))
-                )).await;
-            }
+    /// Rewrites only the description of `previous`, keeping its type, scope and body,
+    /// for when the classification is right but the wording isn't
+    pub async fn reword_commit_message(&self, diff: &str, previous: &str) -> Result<String> {
+        let parts = parse_conventional(previous).ok_or_else(|| {
+            Error::InvalidMessage(vec!["subject does not follow the `type(scope): description` format".to_string()])
+        })?;
+        let instruction = format!(
+            "Use exactly \"{}\" as the type and scope. The previous description was \"{}\"; \
+            write a differently worded description of the same change.",
+            parts.prefix(),
+            parts.description
+        );
+        let reworded = self.generate_raw(diff, diff, Some(&instruction)).await?.message;
+        let description = parse_conventional(&reworded)
+            .map_or_else(|| reworded.lines().next().unwrap_or("").trim().to_string(), |p| p.description);
+
+        let subject = format!("{}: {}", parts.prefix(), description);
+        Ok(match previous.split_once('\n') {
+            Some((_, rest)) => format!("{}\n{}", subject, rest),
+            None => subject,
+        })
+    }
+
+    /// Generates a commit message, with an optional extra instruction, along with how many
+    /// times it was regenerated because of its format or type
+    pub async fn generate(&self, diff: &str, instruction: Option<&str>) -> Result<Generated> {
+        self.generate_from(diff, diff, instruction).await
+    }
+
+    /// Like `generate`, but only `prompt_diff` is sent while the type and scope come from
+    /// the whole `diff`, e.g. when large files are summarized in `instruction` instead
+    pub async fn generate_from(&self, diff: &str, prompt_diff: &str, instruction: Option<&str>) -> Result<Generated> {
+        let mut generated = self.generate_raw(diff, prompt_diff, instruction).await?;
+        if let Some(scope) = self.infer_scope(&self.classify(diff)) {
+            generated.message = with_scope(&generated.message, &scope);
+        }
+        Ok(generated)
+    }
+
+    async fn generate_with_instruction(&self, diff: &str, instruction: Option<&str>) -> Result<String> {
+        Ok(self.generate(diff, instruction).await?.message)
+    }
+
+    /// Prompt asking for the reason behind the change, see `with_explain_why`
+    fn rationale_instruction(&self) -> Option<String> {
+        if !self.explain_why {
+            return None;
+        }
+        Some(match &self.reason {
+            Some(reason) => format!(
+                "Start the body with one sentence explaining why the change was made, \
+                based on this reason given by the author: {}",
+                reason.trim()
+            ),
+            None => "Start the body with one sentence explaining why the change was made, \
+                but only if the reason is evident from the diff. Never invent motivations."
+                .to_string(),
+        })
+    }
+
+    async fn generate_raw(&self, diff: &str, prompt_diff: &str, instruction: Option<&str>) -> Result<Generated> {
+        let context = self.classify(diff);
+        let prompt_diff = self.prompt_diff(prompt_diff);
+        if let Some(path) = &self.sent_diff_path {
+            std::fs::write(path, &prompt_diff)?;
+        }
+        let request = self.commit_request(&context, &prompt_diff, instruction);
 
-            match self.try_generate_message(&request).await {
+        // Each corrective regeneration lowers the temperature; transient errors retry as-is,
+        // each with its own cap
+        let max_corrections = self.config.max_validation_retries;
+        let temperatures = temperature_schedule(self.config.temperature, max_corrections + 1);
+        let mut corrections = 0;
+        let mut network_retries = 0;
+        let mut last_error = None;
+        let mut last_attempt = None;
+        loop {
+            let mut attempt = request.clone();
+            attempt.temperature = temperatures[corrections as usize];
+            info!(corrections, network_retries, temperature = attempt.temperature, "Requesting commit message");
+            match self.try_generate_message(&attempt).await {
                 Ok(message) => {
                     // Pre-validate the message
-                    if !is_conventional_commit(&message) {
+                    if !is_conventional_commit_with_types(self.conventional_part(&message), &self.allowed_types) {
+                        last_attempt = Some(message);
+                        if corrections == max_corrections {
+                            break;
+                        }
+                        corrections += 1;
                         continue; // Try again if format is invalid
                     }
-                    // Validate the type matches the context
-                    let msg_type = message.split(':').next().unwrap_or("")
-                        .split('(').next().unwrap_or("");
-                    if msg_type == context.get_suggested_type() {
-                        return Ok(message);
+                    if corrections == max_corrections || !self.wants_type_retry(&message, &context) {
+                        return Ok(Generated { message, validation_retries: corrections });
                     }
                     // If we get here, the message is valid but doesn't match context
-                    // Try again with a lower temperature
-                    if retry < MAX_RETRIES - 1 {
-                        let mut new_request = request.clone();
-                        new_request.temperature *= 0.8;
-                        if let Ok(new_message) = self.try_generate_message(&new_request).await {
-                            if is_conventional_commit(&new_message) {
-                                return Ok(new_message);
-                            }
+                    // Try again at the next temperature of the schedule
+                    corrections += 1;
+                    attempt.temperature = temperatures[corrections as usize];
+                    info!(temperature = attempt.temperature, "Retrying for a type matching the diff");
+                    if let Ok(new_message) = self.try_generate_message(&attempt).await {
+                        if is_conventional_commit_with_types(self.conventional_part(&new_message), &self.allowed_types) {
+                            return Ok(Generated { message: new_message, validation_retries: corrections });
                         }
                     }
-                    return Ok(message); // Use the original message if retries fail
+                    // Use the original message if retries fail
+                    return Ok(Generated { message, validation_retries: corrections });
                 },
                 Err(e) => {
-                    if let Error::Request(ref req_err) = e {
-                        if let Some(status) = req_err.status() {
-                            if status == StatusCode::SERVICE_UNAVAILABLE 
-                               || status == StatusCode::TOO_MANY_REQUESTS {
-                                last_error = Some(e);
-                                continue;
-                            }
-                        }
+                    let transient = e.status().is_some_and(|status| {
+                        status == StatusCode::SERVICE_UNAVAILABLE || status == StatusCode::TOO_MANY_REQUESTS
+                    });
+                    if !transient {
+                        return Err(e);
+                    }
+                    if network_retries == MAX_RETRIES - 1 {
+                        last_error = Some(e);
+                        break;
                     }
-                    return Err(e);
+                    network_retries += 1;
+                    let delay = Duration::from_millis(INITIAL_RETRY_DELAY_MS * (2_u64.pow(network_retries - 1)));
+                    if let Some(status) = e.status().filter(|_| self.retry_notes) {
+                        eprintln!("{}", retry_note(status, delay, network_retries + 1));
+                    }
+                    tokio::time::sleep(delay).await;
                 }
             }
         }
 
+        // A non-conforming draft is more useful to the caller than a transient error
+        if let Some(last_attempt) = last_attempt {
+            return Err(Error::ValidationExhausted { last_attempt });
+        }
         Err(last_error.unwrap_or_else(|| Error::CommitMessageGeneration(
             "Maximum retries exceeded".to_string()
         )))
     }
 
+    /// The diff as sent to the model: without comment-only changes if configured, and
+    /// truncated to `max_diff_tokens`
+    fn prompt_diff(&self, diff: &str) -> String {
+        let prompt_diff = if self.config.strip_comments {
+            strip_comment_changes(diff)
+        } else {
+            diff.to_string()
+        };
+        truncate_to_tokens(&prompt_diff, self.config.max_diff_tokens)
+    }
+
+    /// The request for a commit message of `prompt_diff`, described by `context`
+    fn commit_request(&self, context: &CommitContext, prompt_diff: &str, instruction: Option<&str>) -> ChatRequest {
+        let remaining_tokens = self.config.max_diff_tokens.saturating_sub(count_tokens(prompt_diff));
+
+        // Templates take `{}` placeholders for the context and then the diff; a template
+        // with a single placeholder only receives the diff
+        let template = &self.config.user_prompt_template;
+        let mut user_prompt = if template.matches("{}").count() == 1 {
+            template.replacen("{}", prompt_diff, 1)
+        } else {
+            template
+                .replacen("{}", &context.to_prompt_context(), 1)
+                .replacen("{}", prompt_diff, 1)
+        };
+        if !self.file_list.is_empty() {
+            user_prompt.push_str("\n\nAll changed files, including any cut from the diff above:");
+            for (status, path) in &self.file_list {
+                user_prompt.push_str(&format!("\n- {} {}", status, path));
+            }
+        }
+        if self.config.length == MessageLength::Short {
+            user_prompt.push_str("\n\nKeep the subject line terse, ideally under 50 characters.");
+        }
+        if self.config.include_body {
+            user_prompt.push_str(
+                "\n\nAfter the subject line, add a blank line followed by a short body \
+                of '- ' bullet points describing the main changes.",
+            );
+            if let Some(instruction) = self.rationale_instruction() {
+                user_prompt.push_str("\n\n");
+                user_prompt.push_str(&instruction);
+            }
+        }
+        if self.allowed_types != CommitConfig::default().allowed_types {
+            user_prompt.push_str(&format!(
+                "\n\nThe type must be one of: {} (this list replaces any other list of types).",
+                self.allowed_types.join(", ")
+            ));
+        }
+        if let Some(scope) = self.infer_scope(context) {
+            user_prompt.push_str(&format!(
+                "\n\nAll changed files are in the '{}' area, so use '{}' as the scope.",
+                scope, scope
+            ));
+        } else if context.is_security_related() {
+            user_prompt.push_str(
+                "\n\nThe changes look security-related (a vulnerability, sanitizing, escaping \
+                or authentication). If they fix a security issue, use 'security' as the scope.",
+            );
+        }
+        if let Some(previous) = &self.previous_diff {
+            let previous = truncate_to_tokens(previous, remaining_tokens);
+            if !previous.trim().is_empty() && remaining_tokens > 0 {
+                user_prompt.push_str(
+                    "\n\nFor context only, this is the diff of the previous change. \
+                    If the diff above fixes a regression introduced there, use a fix type \
+                    and mention what broke.\n\nPrevious change:\n",
+                );
+                user_prompt.push_str(&previous);
+            }
+        }
+        if let Some(instruction) = instruction {
+            user_prompt.push_str("\n\n");
+            user_prompt.push_str(instruction);
+        }
+
+        let mut messages = vec![ChatMessage {
+            role: "system".to_string(),
+            content: self.system_prompt(),
+        }];
+        if let Some(project_context) = self.project_context.as_deref().map(str::trim).filter(|c| !c.is_empty()) {
+            messages.push(ChatMessage {
+                role: "system".to_string(),
+                content: format!("Project context:\n{}", project_context),
+            });
+        }
+        if !self.tree_context.is_empty() {
+            messages.push(ChatMessage {
+                role: "system".to_string(),
+                content: format!(
+                    "Modules and directories of the repository, prefer one of them as the scope:\n{}",
+                    self.tree_context.join(", ")
+                ),
+            });
+        }
+        messages.push(ChatMessage {
+            role: "user".to_string(),
+            content: user_prompt,
+        });
+
+        ChatRequest {
+            model: self.config.model.clone(),
+            messages,
+            temperature: self.config.temperature,
+            max_tokens: self.config.max_tokens,
+            stop: self.config.stop_sequences.clone(),
+            response_format: None,
+        }
+    }
+
+    /// Whether a valid message is worth regenerating because its type differs from the one
+    /// suggested by the diff classification. Never when the model's type is trusted.
+    fn wants_type_retry(&self, message: &str, context: &CommitContext) -> bool {
+        if self.trust_model_type {
+            return false;
+        }
+        let commit_type = parse_conventional(self.conventional_part(message)).map(|parsed| parsed.commit_type);
+        commit_type.as_deref() != Some(context.get_suggested_type())
+    }
+
+    /// The message without the gitmoji it may start with in gitmoji mode
+    fn conventional_part<'a>(&self, message: &'a str) -> &'a str {
+        if self.gitmoji {
+            strip_leading_emoji(message)
+        } else {
+            message
+        }
+    }
+
+    /// The owning workspace crate if there is one, else the Rust module the changes
+    /// point at, else the common directory
+    fn infer_scope(&self, context: &CommitContext) -> Option<String> {
+        let files: Vec<String> = context.new_files.iter().chain(&context.modified_files).cloned().collect();
+        self.repo_root
+            .as_deref()
+            .and_then(|root| CommitContext::infer_cargo_scope(root, &files))
+            .or_else(|| context.infer_rust_scope())
+            .or_else(|| context.infer_scope())
+    }
+
+    fn classify(&self, diff: &str) -> CommitContext {
+        CommitContext::from_diff_with_thresholds(diff, &self.config.classification)
+    }
+
+    /// The configured system prompt wrapped in its optional prefix and suffix
+    fn system_prompt(&self) -> String {
+        [
+            self.config.system_prompt_prefix.as_deref(),
+            Some(self.config.system_prompt.as_str()),
+            self.config.system_prompt_suffix.as_deref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+    }
+
+    /// Chat or completions endpoint of the configured provider
+    fn endpoint(&self, completions: bool) -> String {
+        match (self.config.provider, completions) {
+            (Provider::Together, false) => together_url(&self.config.api_base_url, "chat/completions"),
+            (Provider::Together, true) => together_url(&self.config.api_base_url, "completions"),
+            (Provider::HuggingFace, false) => HuggingFaceProvider::chat_url(&self.config.model),
+            (Provider::HuggingFace, true) => HuggingFaceProvider::completions_url(&self.config.model),
+        }
+    }
+
+    /// Posts a JSON body to the API and decodes the JSON response, logging request and
+    /// response metadata (never the key). With API tracing, the full exchange is logged too.
+    async fn send<T: Serialize, R: DeserializeOwned>(&self, url: &str, body: &T, max_tokens: u32) -> Result<R> {
+        debug!(url, model = %self.config.model, max_tokens, "Sending API request");
+        let body = serde_json::to_value(body)
+            .map_err(|e| Error::RequestSerialization(e.to_string()))?;
+        let body = with_extra_params(body, &self.config.extra_params);
+        if let Some(replay) = &self.replay {
+            let mut exchanges = replay.lock().unwrap_or_else(|e| e.into_inner());
+            let index = exchanges
+                .iter()
+                .position(|exchange| exchange.url == url && exchange.request == body)
+                .ok_or_else(|| Error::Replay(format!("no recorded exchange matches the request to {}", url)))?;
+            let exchange = exchanges.remove(index);
+            return serde_json::from_value(exchange.response)
+                .map_err(|e| Error::Replay(format!("recorded response does not fit the request: {}", e)));
+        }
+        let started = Instant::now();
+        let mut builder = self
+            .client
+            .post(url)
+            .header("Authorization", format!("Bearer {}", self.api_key));
+        if self.config.provider == Provider::HuggingFace {
+            // Block until a cold model is loaded instead of failing with 503
+            builder = builder.header("x-wait-for-model", "true");
+        }
+        let request = builder
+            .headers(build_headers(&self.config.extra_headers)?)
+            .json(&body)
+            .build()?;
+        if self.trace_api {
+            let headers: Vec<String> = request
+                .headers()
+                .iter()
+                .map(|(name, value)| format!("{}: {}", name, mask_header(name.as_str(), value.to_str().unwrap_or("<binary>"))))
+                .collect();
+            let json = serde_json::to_string(&body).unwrap_or_default();
+            debug!(headers = %headers.join(", "), body = %self.mask_key(&json), "API request");
+        }
+
+        let response = self.client.execute(request).await?;
+        debug!(
+            status = %response.status(),
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "Received API response"
+        );
+        let status = response.status();
+        let status_error = response.error_for_status_ref().err();
+        let text = response.text().await?;
+        if self.trace_api {
+            debug!(body = %self.mask_key(&text), "API response");
+        }
+        if let Some(e) = status_error {
+            return Err(provider_error(status, &self.mask_key(&text)).unwrap_or(e.into()));
+        }
+        if let Some(record) = &self.record {
+            let (path, exchanges) = &**record;
+            let mut exchanges = exchanges.lock().unwrap_or_else(|e| e.into_inner());
+            exchanges.push(Exchange {
+                url: url.to_string(),
+                request: body,
+                response: serde_json::from_str(&text)?,
+            });
+            crate::recording::save(path, &exchanges)?;
+        }
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    /// Replaces every occurrence of the API key, in case a provider echoes it back
+    fn mask_key(&self, text: &str) -> String {
+        if self.api_key.is_empty() {
+            return text.to_string();
+        }
+        text.replace(&self.api_key, "***")
+    }
+
     async fn try_generate_message(&self, request: &ChatRequest) -> Result<String> {
+        if self.config.prompt_style != PromptStyle::Chat {
+            return self.try_generate_completion(request).await;
+        }
+        if self.config.structured_output && !self.json_mode_rejected.load(Ordering::Relaxed) {
+            match self.try_generate_structured(request).await {
+                Err(e) if matches!(e.status(), Some(StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY)) =>
+                {
+                    warn!("Provider rejected JSON mode, falling back to plain output: {}", e);
+                    self.json_mode_rejected.store(true, Ordering::Relaxed);
+                }
+                result => return result,
+            }
+        }
+
         let response = self
-            .client
-            .post(API_URL)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .json(request)
-            .send()
-            .await?
-            .error_for_status()?
-            .json::<ChatResponse>()
+            .send::<_, ChatResponse>(&self.endpoint(false), request, request.max_tokens)
             .await?;
 
         response
             .choices
             .first()
-            .map(|choice| choice.message.content.trim().to_string())
+            .map(|choice| normalize_message(&choice.message.content, self.gitmoji))
+            .ok_or_else(|| Error::CommitMessageGeneration("No response from API".to_string()))
+    }
+
+    /// Requests a JSON object in JSON mode and assembles the message from its fields.
+    /// Content that isn't the expected JSON is treated as a plain message.
+    async fn try_generate_structured(&self, request: &ChatRequest) -> Result<String> {
+        let mut request = request.clone();
+        request.response_format = Some(ResponseFormat { kind: "json_object" });
+        // Pretty-printed JSON would be cut at the first newline
+        request.stop.clear();
+        if let Some(user) = request.messages.iter_mut().rev().find(|m| m.role == "user") {
+            user.content.push_str("\n\n");
+            user.content.push_str(STRUCTURED_INSTRUCTION);
+        }
+
+        let response = self
+            .send::<_, ChatResponse>(&self.endpoint(false), &request, request.max_tokens)
+            .await?;
+
+        let content = response
+            .choices
+            .first()
+            .map(|choice| choice.message.content.as_str())
+            .ok_or_else(|| Error::CommitMessageGeneration("No response from API".to_string()))?;
+        Ok(parse_structured_message(content, self.gitmoji))
+    }
+
+    async fn try_generate_completion(&self, request: &ChatRequest) -> Result<String> {
+        let completion = CompletionRequest {
+            model: &request.model,
+            prompt: format_prompt(self.config.prompt_style, &request.messages),
+            temperature: request.temperature,
+            max_tokens: request.max_tokens,
+            stop: &request.stop,
+        };
+
+        let response = self
+            .send::<_, CompletionResponse>(&self.endpoint(true), &completion, completion.max_tokens)
+            .await?;
+
+        response
+            .choices
+            .first()
+            .map(|choice| normalize_message(&choice.text, self.gitmoji))
             .ok_or_else(|| Error::CommitMessageGeneration("No response from API".to_string()))
     }
-} 
\ No newline at end of file
+}
+
+/// Adds `extra_params` to a request body, skipping fields the body already sets
+fn with_extra_params(
+    mut body: serde_json::Value,
+    extra_params: &serde_json::Map<String, serde_json::Value>,
+) -> serde_json::Value {
+    if let Some(fields) = body.as_object_mut() {
+        for (key, value) in extra_params {
+            if fields.contains_key(key) {
+                debug!(param = %key, "Ignoring extra param already set by the request");
+                continue;
+            }
+            fields.insert(key.clone(), value.clone());
+        }
+    }
+    body
+}
+
+/// Masks the whole value of headers that carry credentials, so no part of a secret is logged
+fn mask_header(name: &str, value: &str) -> String {
+    let name = name.to_ascii_lowercase();
+    if ["auth", "key", "token", "secret", "cookie"].iter().any(|s| name.contains(s)) {
+        "***".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Temperatures for successive attempts: the configured one first, then stepping
+/// linearly toward 0 across the remaining retries
+pub fn temperature_schedule(initial: f32, attempts: u32) -> Vec<f32> {
+    (0..attempts)
+        .map(|i| initial * (attempts - i) as f32 / attempts as f32)
+        .collect()
+}
+
+/// Assembles the message from a JSON mode response, or normalizes it as plain text
+/// when it isn't the expected object
+fn parse_structured_message(content: &str, gitmoji: bool) -> String {
+    let json = normalize_message(content, gitmoji);
+    match serde_json::from_str::<StructuredMessage>(&json) {
+        Ok(structured) => structured.to_message(),
+        Err(e) => {
+            debug!("JSON mode response was not a commit message object: {}", e);
+            json
+        }
+    }
+}
+
+/// The provider's explanation of a failed request, from an `{"error": {"message": ...}}`
+/// (Together.ai, OpenAI-style) or `{"error": "..."}` (Hugging Face) body
+fn provider_error(status: StatusCode, body: &str) -> Option<Error> {
+    let body: serde_json::Value = serde_json::from_str(body).ok()?;
+    let error = &body["error"];
+    let message = error["message"].as_str().or_else(|| error.as_str())?.trim();
+    (!message.is_empty()).then(|| Error::ApiResponse { status, message: message.to_string() })
+}
+
+/// Like `Response::error_for_status`, but reads the body of a failed request first so
+/// the provider's explanation isn't lost
+pub(crate) async fn error_for_status(response: reqwest::Response) -> Result<reqwest::Response> {
+    let Err(e) = response.error_for_status_ref() else {
+        return Ok(response);
+    };
+    let status = response.status();
+    let text = response.text().await.unwrap_or_default();
+    Err(provider_error(status, &text).unwrap_or(e.into()))
+}
+
+/// Replaces (or adds) the scope of a conventional commit subject
+fn with_scope(message: &str, scope: &str) -> String {
+    let Some(mut parsed) = parse_conventional(message) else {
+        return message.to_string();
+    };
+    parsed.scope = Some(scope.to_string());
+    match message.split_once('\n') {
+        Some((_, rest)) => format!("{}\n{}", parsed.subject(), rest),
+        None => parsed.subject(),
+    }
+}
+
+/// Strips cosmetic wrapping models like to add around the message: markdown fences,
+/// surrounding backticks or quotes, a leading `Commit message:` label and, unless
+/// `gitmoji` is on, a leading emoji that would only fail validation
+pub fn normalize_message(raw: &str, gitmoji: bool) -> String {
+    const LABELS: [&str; 2] = ["commit message:", "message:"];
+    const WRAPPERS: [(char, char); 5] =
+        [('`', '`'), ('"', '"'), ('\'', '\''), ('“', '”'), ('‘', '’')];
+
+    let mut message = raw.trim().to_string();
+    loop {
+        let before = message.clone();
+
+        // Markdown fences, optionally with a language tag on the opening line
+        if let Some(inner) = message.strip_prefix("```").and_then(|m| m.strip_suffix("```")) {
+            let inner = match inner.split_once('\n') {
+                Some((tag, rest)) if !tag.contains(' ') && !tag.contains(':') => rest,
+                _ => inner,
+            };
+            message = inner.trim().to_string();
+        }
+
+        for label in LABELS {
+            if message.len() >= label.len()
+                && message.is_char_boundary(label.len())
+                && message[..label.len()].eq_ignore_ascii_case(label)
+            {
+                message = message[label.len()..].trim().to_string();
+            }
+        }
+
+        for (open, close) in WRAPPERS {
+            if message.chars().count() >= 2 && message.starts_with(open) && message.ends_with(close) {
+                let inner = &message[open.len_utf8()..message.len() - close.len_utf8()];
+                message = inner.trim().to_string();
+            }
+        }
+
+        if !gitmoji {
+            message = strip_leading_emoji(&message).to_string();
+        }
+
+        if message == before {
+            return message;
+        }
+    }
+}
+
+/// Drops a leading emoji, or gitmoji shortcode like `:bug:`, and the whitespace after it
+pub fn strip_leading_emoji(message: &str) -> &str {
+    let is_emoji = |c: char| {
+        matches!(c, '\u{1F000}'..='\u{1FAFF}' | '\u{2600}'..='\u{27BF}' | '\u{2B00}'..='\u{2BFF}')
+            || matches!(c, '\u{FE0F}' | '\u{200D}' | '\u{20E3}')
+    };
+    let rest = message.trim_start_matches(is_emoji);
+    let rest = match rest.strip_prefix(':').and_then(|r| r.split_once(':')) {
+        Some((code, after)) if !code.is_empty()
+            && after.starts_with(char::is_whitespace)
+            && code.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "_+-".contains(c)) => after,
+        _ => rest,
+    };
+    if rest.len() == message.len() {
+        return message;
+    }
+    rest.trim_start()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    #[test_case("feat: add x"; "plain")]
+    #[test_case("`feat: add x`"; "backticks")]
+    #[test_case("\"feat: add x\""; "double quotes")]
+    #[test_case("'feat: add x'"; "single quotes")]
+    #[test_case("Commit message: feat: add x"; "label")]
+    #[test_case("```\nfeat: add x\n```"; "fence")]
+    #[test_case("```text\nfeat: add x\n```"; "fence with language")]
+    #[test_case("Commit message: `\"feat: add x\"`"; "nested")]
+    #[test_case("✨ feat: add x"; "emoji")]
+    #[test_case("`✨ feat: add x`"; "emoji in backticks")]
+    #[test_case(":sparkles: feat: add x"; "shortcode")]
+    fn test_normalize_message(raw: &str) {
+        assert_eq!(normalize_message(raw, false), "feat: add x");
+    }
+
+    #[test]
+    fn test_stray_emoji_passes_validation() {
+        let message = normalize_message("🐛 fix: x", false);
+        assert_eq!(message, "fix: x");
+        assert!(is_conventional_commit_with_types(&message, &CommitConfig::default().allowed_types));
+        assert_eq!(normalize_message("fix: handle :memo: in names", false), "fix: handle :memo: in names");
+    }
+
+    #[test]
+    fn test_gitmoji_is_kept_in_gitmoji_mode() {
+        let message = normalize_message("`🐛 fix: x`", true);
+        assert_eq!(message, "🐛 fix: x");
+        let client = AiClient::new(String::new(), AiConfig::default()).with_gitmoji(true);
+        assert!(is_conventional_commit_with_types(client.conventional_part(&message), &client.allowed_types));
+    }
+
+    #[test]
+    fn test_infer_scope_single_directory() {
+        let diff = "diff --git a/src/auth/login.rs b/src/auth/login.rs\n\
+            diff --git a/src/auth/token/jwt.rs b/src/auth/token/jwt.rs\n\
+            new file mode 100644\n";
+        assert_eq!(CommitContext::from_diff(diff).infer_scope(), Some("auth".to_string()));
+    }
+
+    #[test]
+    fn test_infer_scope_mixed_directories() {
+        let diff = "diff --git a/src/auth/login.rs b/src/auth/login.rs\n\
+            diff --git a/src/api/routes.rs b/src/api/routes.rs\n";
+        assert_eq!(CommitContext::from_diff(diff).infer_scope(), None);
+    }
+
+    #[test]
+    fn test_infer_rust_scope_from_new_module() {
+        let diff = "diff --git a/src/lib.rs b/src/lib.rs\n\
+            +pub mod auth;\n\
+            +pub use crate::auth::Session;\n\
+            diff --git a/src/auth.rs b/src/auth.rs\n\
+            new file mode 100644\n\
+            +use crate::Error;\n\
+            +pub struct Session;\n";
+        let context = CommitContext::from_diff(diff);
+        assert_eq!(context.infer_scope(), None);
+        assert_eq!(context.infer_rust_scope(), Some("auth".to_string()));
+        assert_eq!(context.heuristic_message(), "feat(auth): update 2 files");
+    }
+
+    #[test_case("+use crate::git::GitRepo;\n-use crate::git::open;\n+    crate::git::diff(&repo)?;", Some("git"); "paths")]
+    #[test_case("+use crate::git::GitRepo;\n+use crate::ai::AiClient;", None; "too few paths")]
+    #[test_case("+mod git;\n+use crate::ai::AiClient;\n+let x = crate::ai::x();\n+crate::ai::y();", None; "no dominant module")]
+    #[test_case("+use std::fmt;\n+use crate::Error;\n+use self::Kind::*;", None; "items and external crates")]
+    fn test_infer_rust_scope_from_paths(lines: &str, expected: Option<&str>) {
+        let diff = format!("diff --git a/src/main.rs b/src/main.rs\n{}\n", lines);
+        assert_eq!(CommitContext::from_diff(&diff).infer_rust_scope().as_deref(), expected);
+    }
+
+    #[test]
+    fn test_infer_rust_scope_only_for_rust_diffs() {
+        let diff = "diff --git a/src/lib.rs b/src/lib.rs\n\
+            +mod auth;\n\
+            diff --git a/README.md b/README.md\n\
+            +Auth docs\n\
+            diff --git a/docs/auth.md b/docs/auth.md\n\
+            +More auth docs\n";
+        assert_eq!(CommitContext::from_diff(diff).infer_rust_scope(), None);
+    }
+
+    #[test_case("feat: add login", "feat(auth): add login"; "no scope")]
+    #[test_case("feat(core)!: drop v1\n\nbody", "feat(auth)!: drop v1\n\nbody"; "breaking with body")]
+    fn test_with_scope(message: &str, expected: &str) {
+        assert_eq!(with_scope(message, "auth"), expected);
+    }
+
+    #[test_case(r#"{"type": "feat", "scope": "auth", "description": "add login."}"#, "feat(auth): add login"; "with scope")]
+    #[test_case(r#"{"type": "Fix", "scope": null, "description": " handle empty diff"}"#, "fix: handle empty diff"; "null scope")]
+    #[test_case(r#"{"type": "feat", "scope": "", "description": "add x", "body": "- one"}"#, "feat: add x\n\n- one"; "with body")]
+    #[test_case("```json\n{\"type\": \"docs\", \"description\": \"fix typo\"}\n```", "docs: fix typo"; "fenced")]
+    #[test_case("feat: add x", "feat: add x"; "plain text fallback")]
+    fn test_parse_structured_message(content: &str, expected: &str) {
+        assert_eq!(parse_structured_message(content, false), expected);
+    }
+
+    #[test]
+    fn test_summary() {
+        let diff = "diff --git a/src/lib.rs b/src/lib.rs\n+a\n+b\n-c\n\
+            diff --git a/src/old.rs b/src/old.rs\ndeleted file mode 100644\n-d\n";
+        assert_eq!(
+            CommitContext::from_diff(diff).summary(),
+            "2 files changed, +2/-2, suggested type: feat"
+        );
+    }
+
+    #[test]
+    fn test_temperature_schedule_steps_toward_zero() {
+        let schedule = temperature_schedule(0.6, 3);
+        assert_eq!(schedule.len(), 3);
+        for (actual, expected) in schedule.iter().zip([0.6, 0.4, 0.2]) {
+            assert!((actual - expected).abs() < 1e-6, "{:?}", schedule);
+        }
+    }
+
+    #[test_case("Authorization", "Bearer sk-123", "***"; "authorization")]
+    #[test_case("X-Api-Key", "sk-123", "***"; "api key")]
+    #[test_case("Content-Type", "application/json", "application/json"; "plain header")]
+    fn test_mask_header(name: &str, value: &str, expected: &str) {
+        assert_eq!(mask_header(name, value), expected);
+    }
+
+    #[test]
+    fn test_mask_key_hides_echoed_key() {
+        let client = AiClient::new("sk-secret".to_string(), AiConfig::default());
+        assert_eq!(client.mask_key(r#"{"error":"bad key sk-secret"}"#), r#"{"error":"bad key ***"}"#);
+    }
+
+    #[test]
+    fn test_churn_is_not_a_large_feature() {
+        // A block moved within the file: 120 lines out, the same 120 back in, plus one new line
+        let moved: Vec<String> = (0..120).map(|i| format!("    step_{}();", i)).collect();
+        let removed: String = moved.iter().map(|line| format!("-{}\n", line)).collect();
+        let added: String = moved.iter().map(|line| format!("+{}\n", line)).collect();
+        let diff = format!("diff --git a/src/run.rs b/src/run.rs\n{}{}+    finish();\n", removed, added);
+
+        let context = CommitContext::from_diff(&diff);
+        assert_eq!((context.total_additions, context.total_deletions), (121, 120));
+        assert_eq!((context.net_additions, context.net_deletions), (1, 0));
+        assert_eq!(context.commit_type, "standard change");
+        assert!(context.to_prompt_context().contains("net 1 additions and 0 deletions"));
+    }
+
+    #[test]
+    fn test_lines_moved_between_files_still_count() {
+        let lines: String = (0..120).map(|i| format!("line {}\n", i)).collect();
+        let removed: String = lines.lines().map(|line| format!("-{}\n", line)).collect();
+        let added: String = lines.lines().map(|line| format!("+{}\n", line)).collect();
+        let diff = format!(
+            "diff --git a/src/a.rs b/src/a.rs\n{}diff --git a/src/b.rs b/src/b.rs\n{}",
+            removed, added
+        );
+        let context = CommitContext::from_diff(&diff);
+        assert_eq!((context.net_additions, context.net_deletions), (120, 120));
+    }
+
+    #[test]
+    fn test_thresholds_change_classification() {
+        let added: String = (0..50).map(|i| format!("+line {}\n", i)).collect();
+        let diff = format!("diff --git a/src/lib.rs b/src/lib.rs\n{}", added);
+        assert_eq!(CommitContext::from_diff(&diff).commit_type, "standard change");
+
+        let thresholds = ClassificationThresholds {
+            large_additions_threshold: 40,
+            ..ClassificationThresholds::default()
+        };
+        let context = CommitContext::from_diff_with_thresholds(&diff, &thresholds);
+        assert_eq!(context.commit_type, "large feature implementation");
+
+        let diff = "diff --git a/src/lib.rs b/src/lib.rs\n+a\n-b\n-c\n";
+        let thresholds = ClassificationThresholds {
+            refactor_deletion_ratio: 1.5,
+            ..ClassificationThresholds::default()
+        };
+        assert_eq!(CommitContext::from_diff(diff).commit_type, "standard change");
+        assert_eq!(CommitContext::from_diff_with_thresholds(diff, &thresholds).commit_type, "major refactoring");
+    }
+
+    #[test]
+    fn test_heuristic_message() {
+        let diff = "diff --git a/src/auth/login.rs b/src/auth/login.rs\n+a\n\
+            diff --git a/src/auth/token.rs b/src/auth/token.rs\n+b\n";
+        assert_eq!(CommitContext::from_diff(diff).heuristic_message(), "feat(auth): update 2 files");
+    }
+
+    #[test]
+    fn test_infer_cargo_scope_in_workspace() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |path: &str, content: &str| {
+            let path = dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        };
+        write("Cargo.toml", "[workspace]\nmembers = [\"crates/*\"]\n");
+        write("crates/core/Cargo.toml", "[package]\nname = \"sage-core\"\n");
+        write("crates/cli/Cargo.toml", "[package]\nname = \"sage-cli\"\n");
+
+        let files = |paths: &[&str]| paths.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            CommitContext::infer_cargo_scope(dir.path(), &files(&["crates/core/src/lib.rs", "crates/core/Cargo.toml"])),
+            Some("sage-core".to_string())
+        );
+        assert_eq!(
+            CommitContext::infer_cargo_scope(dir.path(), &files(&["crates/core/src/lib.rs", "crates/cli/src/main.rs"])),
+            None
+        );
+        assert_eq!(CommitContext::infer_cargo_scope(dir.path(), &files(&["README.md"])), None);
+    }
+
+    #[test_case("+let clean = sanitize(input);\n+let html = escape(clean);\n+require_auth(&request)?;\n", true; "several weak signals")]
+    #[test_case("+// Fixes CVE-2024-1234\n", true; "cve reference")]
+    #[test_case("+let safe = sanitize(input);\n", false; "single weak signal")]
+    #[test_case("+let author = commit.author();\n+let authors = list();\n+let a = author2;\n", false; "author is not auth")]
+    fn test_security_detection(diff: &str, expected: bool) {
+        let diff = format!("diff --git a/src/lib.rs b/src/lib.rs\n{}", diff);
+        assert_eq!(CommitContext::from_diff(&diff).is_security_related(), expected);
+    }
+
+    #[test_case(StatusCode::TOO_MANY_REQUESTS, 2000, 3, "Rate limited, retrying in 2s (attempt 3/3)" ; "rate limited")]
+    #[test_case(StatusCode::SERVICE_UNAVAILABLE, 1000, 2, "Service unavailable, retrying in 1s (attempt 2/3)" ; "unavailable")]
+    fn test_retry_note(status: StatusCode, delay_ms: u64, attempt: u32, expected: &str) {
+        assert_eq!(retry_note(status, Duration::from_millis(delay_ms), attempt), expected);
+    }
+
+    #[test_case("diff --git a/.github/workflows/ci.yml b/.github/workflows/ci.yml\n-    runs-on: ubuntu-20.04\n+    runs-on: ubuntu-22.04\n", "ci" ; "workflow")]
+    #[test_case("diff --git a/Jenkinsfile b/Jenkinsfile\n+stage('lint') {}\n", "ci" ; "jenkinsfile")]
+    #[test_case("diff --git a/.github/workflows/ci.yml b/.github/workflows/ci.yml\n+    - run: cargo test\n\
+                 diff --git a/src/lib.rs b/src/lib.rs\n+fn x() {}\n", "feat" ; "mixed with code")]
+    fn test_ci_config_changes(diff: &str, expected: &str) {
+        assert_eq!(CommitContext::from_diff(diff).get_suggested_type(), expected);
+    }
+
+    #[test]
+    fn test_custom_ci_paths() {
+        let diff = "diff --git a/ci/build.sh b/ci/build.sh\n+cargo build --release\n";
+        let thresholds = ClassificationThresholds {
+            ci_paths: vec!["ci/*".to_string()],
+            ..ClassificationThresholds::default()
+        };
+        assert_eq!(CommitContext::from_diff(diff).get_suggested_type(), "feat");
+        assert_eq!(CommitContext::from_diff_with_thresholds(diff, &thresholds).get_suggested_type(), "ci");
+    }
+
+    #[test]
+    fn test_deleted_files_suggest_chore() {
+        let diff = "diff --git a/src/legacy/parser.rs b/src/legacy/parser.rs\n\
+                    deleted file mode 100644\n--- a/src/legacy/parser.rs\n+++ /dev/null\n-fn a() {}\n-fn b() {}\n\
+                    diff --git a/src/legacy/lexer.rs b/src/legacy/lexer.rs\n\
+                    deleted file mode 100644\n--- a/src/legacy/lexer.rs\n+++ /dev/null\n-fn c() {}\n-fn d() {}\n\
+                    diff --git a/src/lib.rs b/src/lib.rs\n--- a/src/lib.rs\n+++ b/src/lib.rs\n-mod legacy;\n";
+        let context = CommitContext::from_diff(diff);
+        assert_eq!(context.deleted_files, vec!["src/legacy/parser.rs", "src/legacy/lexer.rs"]);
+        assert_eq!(context.get_suggested_type(), "chore");
+        assert_eq!(context.heuristic_message(), "chore(legacy): remove parser and lexer");
+        assert!(context.to_prompt_context().contains("src/legacy/parser.rs, src/legacy/lexer.rs"));
+    }
+
+    #[test]
+    fn test_whitespace_only_diff_is_style_change() {
+        let diff = "diff --git a/src/lib.rs b/src/lib.rs\n\
+            -fn main() { run(1,2); }\n\
+            +fn main() {\n\
+            +    run(1, 2);\n\
+            +}\n";
+        let context = CommitContext::from_diff(diff);
+        assert_eq!(context.get_suggested_type(), "style");
+
+        let diff = "diff --git a/src/lib.rs b/src/lib.rs\n-run(1, 2);\n+run(1, 3);\n";
+        assert_ne!(CommitContext::from_diff(diff).get_suggested_type(), "style");
+    }
+
+    #[test]
+    fn test_local_message_for_single_file() {
+        let diff = "diff --git a/docs/guide.md b/docs/guide.md\n-teh\n+the\n";
+        let context = CommitContext::from_diff(diff);
+        assert_eq!(context.changed_lines(), 2);
+        assert_eq!(context.local_message(), "docs: minor edits to guide.md");
+    }
+
+    #[test]
+    fn test_format_prompt_llama2() {
+        let messages = vec![
+            ChatMessage { role: "system".to_string(), content: "Be terse.".to_string() },
+            ChatMessage { role: "user".to_string(), content: "Describe +x".to_string() },
+        ];
+        assert_eq!(
+            format_prompt(PromptStyle::Llama2, &messages),
+            "<s>[INST] <<SYS>>\nBe terse.\n<</SYS>>\n\nDescribe +x [/INST]"
+        );
+        assert_eq!(
+            format_prompt(PromptStyle::MistralInstruct, &messages),
+            "<s>[INST] Be terse.\n\nDescribe +x [/INST]"
+        );
+    }
+
+    #[test]
+    fn test_rationale_instruction_uses_the_given_reason() {
+        let client = AiClient::new("key".to_string(), AiConfig::default());
+        assert_eq!(client.rationale_instruction(), None);
+
+        let guessing = AiClient::new("key".to_string(), AiConfig::default()).with_explain_why(None);
+        assert!(guessing.rationale_instruction().unwrap().contains("Never invent motivations"));
+
+        let reasoned = AiClient::new("key".to_string(), AiConfig::default())
+            .with_explain_why(Some("users hit timeouts on slow networks".to_string()));
+        assert!(reasoned
+            .rationale_instruction()
+            .unwrap()
+            .ends_with("given by the author: users hit timeouts on slow networks"));
+    }
+
+    #[test]
+    fn test_trusted_model_type_skips_type_retry() {
+        let context = CommitContext::from_diff("diff --git a/src/lib.rs b/src/lib.rs\n+fn x() {}\n");
+        assert_eq!(context.get_suggested_type(), "feat");
+
+        let client = AiClient::new("key".to_string(), AiConfig::default());
+        assert!(!client.wants_type_retry("fix: handle empty input", &context));
+
+        let strict = AiClient::new("key".to_string(), AiConfig::default()).with_trust_model_type(false);
+        assert!(strict.wants_type_retry("fix: handle empty input", &context));
+        assert!(!strict.wants_type_retry("feat: handle empty input", &context));
+    }
+
+    #[test]
+    fn test_extra_params_do_not_override_request_fields() {
+        let body = serde_json::json!({ "model": "m", "temperature": 0.3 });
+        let extra = serde_json::json!({ "temperature": 1.5, "top_p": 0.9 });
+        assert_eq!(
+            with_extra_params(body, extra.as_object().unwrap()),
+            serde_json::json!({ "model": "m", "temperature": 0.3, "top_p": 0.9 })
+        );
+    }
+
+    #[test]
+    fn test_project_context_is_capped() {
+        let context = "The billing service calls invoices \"bills\".\n".repeat(500);
+        let client = AiClient::new("key".to_string(), AiConfig::default()).with_project_context(context);
+        let kept = client.project_context.unwrap();
+        assert!(count_tokens(&kept) <= MAX_PROJECT_CONTEXT_TOKENS + 10);
+        assert!(kept.ends_with("[... truncated]\n"));
+    }
+
+    #[test_case(r#"{"error": {"message": "max_tokens too large for model", "type": "invalid_request_error"}}"#, Some("max_tokens too large for model") ; "together")]
+    #[test_case(r#"{"error": "Model is overloaded"}"#, Some("Model is overloaded") ; "huggingface")]
+    #[test_case(r#"{"error": {"code": 400}}"#, None ; "no message")]
+    #[test_case("Bad Gateway", None ; "not json")]
+    fn test_provider_error(body: &str, expected: Option<&str>) {
+        let error = provider_error(StatusCode::BAD_REQUEST, body);
+        assert_eq!(error.as_ref().and_then(Error::status), expected.map(|_| StatusCode::BAD_REQUEST));
+        assert_eq!(
+            error.map(|e| e.to_string()),
+            expected.map(|message| format!("API error (400 Bad Request): {}", message))
+        );
+    }
+
+    #[test]
+    fn test_tree_context_is_capped() {
+        let names = (0..100).map(|i| format!("src/module{}", i)).collect();
+        let client = AiClient::new("key".to_string(), AiConfig::default()).with_tree_context(names);
+        assert_eq!(client.tree_context.len(), MAX_TREE_ENTRIES);
+        assert_eq!(client.tree_context[0], "src/module0");
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_generation_returns_cancelled() {
+        let client = AiClient::new("key".to_string(), AiConfig::default());
+        let token = CancellationToken::new();
+        token.cancel();
+        let result = client.generate_commit_message_cancellable("+x", &token).await;
+        assert!(matches!(result, Err(Error::Cancelled)));
+    }
+
+    #[tokio::test]
+    async fn test_replay_answers_without_calling_the_api() {
+        let client = AiClient::new(String::new(), AiConfig::default());
+        let diff = "diff --git a/src/lib.rs b/src/lib.rs\n+fn greet() {}\n";
+        let exchanges = replayed(&client, diff, &["feat: add greeting"]);
+        let client = client.with_replay(exchanges);
+        assert_eq!(client.generate_commit_message(diff).await.unwrap(), "feat: add greeting");
+        assert!(matches!(client.generate_commit_message(diff).await, Err(Error::Replay(_))));
+    }
+
+    #[tokio::test]
+    async fn test_replay_matches_requests_not_order() {
+        let client = AiClient::new(String::new(), AiConfig::default());
+        let greet = "diff --git a/src/lib.rs b/src/lib.rs\n+fn greet() {}\n";
+        let parse = "diff --git a/src/parser.rs b/src/parser.rs\n+fn parse() {}\n";
+        let mut exchanges = replayed(&client, greet, &["feat: add greeting"]);
+        exchanges.extend(replayed(&client, parse, &["feat: add parser"]));
+        let client = client.with_replay(exchanges);
+
+        assert_eq!(client.generate_commit_message(parse).await.unwrap(), "feat: add parser");
+        assert_eq!(client.generate_commit_message(greet).await.unwrap(), "feat: add greeting");
+        let other = "diff --git a/src/lib.rs b/src/lib.rs\n+fn wave() {}\n";
+        assert!(matches!(client.generate_commit_message(other).await, Err(Error::Replay(_))));
+    }
+
+    fn exchange(url: String, request: &impl Serialize, content: &str) -> Exchange {
+        Exchange {
+            url,
+            request: serde_json::to_value(request).unwrap(),
+            response: serde_json::json!({ "choices": [{ "message": { "content": content } }] }),
+        }
+    }
+
+    /// Exchanges answering the successive attempts at a commit message for `diff`
+    fn replayed(client: &AiClient, diff: &str, contents: &[&str]) -> Vec<Exchange> {
+        let request = client.commit_request(&client.classify(diff), &client.prompt_diff(diff), None);
+        let temperatures = temperature_schedule(client.config.temperature, client.config.max_validation_retries + 1);
+        contents
+            .iter()
+            .zip(temperatures)
+            .map(|(content, temperature)| {
+                exchange(client.endpoint(false), &ChatRequest { temperature, ..request.clone() }, content)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_generate_from_classifies_the_whole_diff() {
+        let client = AiClient::new(String::new(), AiConfig::default());
+        let kept = "diff --git a/src/auth/login.rs b/src/auth/login.rs\n+fn login() {}\n";
+        let diff = format!("{}diff --git a/src/billing/invoice.rs b/src/billing/invoice.rs\n+fn bill() {{}}\n", kept);
+        let summaries = "- src/billing/invoice.rs: Adds billing.";
+        let request = client.commit_request(&client.classify(&diff), &client.prompt_diff(kept), Some(summaries));
+        let exchanges = vec![exchange(client.endpoint(false), &request, "feat: add login and billing")];
+        let client = client.with_replay(exchanges);
+
+        let generated = client.generate_from(&diff, kept, Some(summaries)).await.unwrap();
+        assert_eq!(generated.message, "feat: add login and billing");
+    }
+
+    #[test_case(0, None, 0; "no regeneration")]
+    #[test_case(1, None, 1; "one regeneration still invalid")]
+    #[test_case(2, Some("feat: add greeting"), 2; "valid on the last regeneration")]
+    #[tokio::test]
+    async fn test_max_validation_retries(max: u32, expected: Option<&str>, retries: u32) {
+        let config = AiConfig { max_validation_retries: max, ..AiConfig::default() };
+        let client = AiClient::new(String::new(), config);
+        let diff = "diff --git a/src/lib.rs b/src/lib.rs\n+fn greet() {}\n";
+        let exchanges = replayed(&client, diff, &["added greeting", "greeting added", "feat: add greeting"]);
+        let client = client.with_replay(exchanges);
+
+        match (client.generate(diff, None).await, expected) {
+            (Ok(generated), Some(expected)) => {
+                assert_eq!(generated.message, expected);
+                assert_eq!(generated.validation_retries, retries);
+            }
+            // Every regeneration was spent
+            (Err(Error::ValidationExhausted { .. }), None) => assert_eq!(max, retries),
+            (result, _) => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test_case(None, false, "https://api.together.xyz/v1/chat/completions"; "default chat")]
+    #[test_case(None, true, "https://api.together.xyz/v1/completions"; "default completions")]
+    #[test_case(Some("https://eu.proxy.example.com/together/v1/"), false, "https://eu.proxy.example.com/together/v1/chat/completions"; "custom base")]
+    fn test_endpoint_honors_api_base_url(base_url: Option<&str>, completions: bool, expected: &str) {
+        let mut config = AiConfig::default();
+        if let Some(base_url) = base_url {
+            config.api_base_url = base_url.to_string();
+        }
+        assert_eq!(AiClient::new(String::new(), config).endpoint(completions), expected);
+    }
+
+    #[tokio::test]
+    async fn test_summarize_file_diff() {
+        let client = AiClient::new(String::new(), AiConfig::default());
+        let request = client.summary_request("src/auth.rs", "+fn refresh() {}\n");
+        let exchanges = vec![exchange(client.endpoint(false), &request, " Adds a retry loop around token refresh. ")];
+        let client = client.with_replay(exchanges);
+        let summary = client.summarize_file_diff("src/auth.rs", "+fn refresh() {}\n").await.unwrap();
+        assert_eq!(summary, "Adds a retry loop around token refresh.");
+    }
+
+    #[tokio::test]
+    async fn test_sent_diff_is_dumped_after_truncation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sent.diff");
+        let config = AiConfig { max_diff_tokens: 50, ..AiConfig::default() };
+        let client = AiClient::new(String::new(), config);
+        let added: String = (0..200).map(|i| format!("+fn greet_{}() {{}}\n", i)).collect();
+        let diff = format!("diff --git a/src/lib.rs b/src/lib.rs\n{}", added);
+        let exchanges = replayed(&client, &diff, &["feat: add greetings"]);
+        let client = client.with_replay(exchanges).with_sent_diff_dump(path.clone());
+
+        client.generate_commit_message(&diff).await.unwrap();
+        let sent = std::fs::read_to_string(&path).unwrap();
+        assert!(sent.starts_with("diff --git a/src/lib.rs b/src/lib.rs\n+fn greet_0() {}"));
+        assert!(sent.len() < diff.len());
+    }
+
+    #[test]
+    fn test_issue_footers_are_deduplicated() {
+        let diff = "diff --git a/src/lib.rs b/src/lib.rs\n\
+            +// closes #42\n\
+            +// Fixes #42 and resolves #7.\n\
+            -// closes #1\n";
+        assert_eq!(issue_footers(diff), vec!["Closes #42", "Resolves #7"]);
+    }
+}
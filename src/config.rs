@@ -1,27 +1,515 @@
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use crate::{Error, Result};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Environment variables read by [`Config::apply_env_overrides`]
+pub const ENV_MODEL: &str = "COMMIT_SAGE_MODEL";
+pub const ENV_TEMPERATURE: &str = "COMMIT_SAGE_TEMPERATURE";
+pub const ENV_MAX_TOKENS: &str = "COMMIT_SAGE_MAX_TOKENS";
+
+/// Fewest `max_tokens` that fit a subject line; below it the model stops mid-word and
+/// every attempt fails validation
+pub const MIN_MAX_TOKENS: u32 = 20;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Config {
     pub ai: AiConfig,
     pub git: GitConfig,
     pub commit: CommitConfig,
 }
 
+impl Config {
+    /// Parses a TOML configuration, reporting the line, column and field of any error
+    pub fn from_toml_str(source: &str) -> Result<Self> {
+        Self::from_toml_layers(&[source])
+    }
+
+    /// Builds the configuration from TOML layers, such as the global file and then the
+    /// project's, merged in order with [`Config::merge`] on top of the defaults
+    pub fn from_toml_layers(layers: &[&str]) -> Result<Self> {
+        let layers = layers
+            .iter()
+            .map(|layer| parse_layer(layer, ConfigFormat::Toml))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(layers.into_iter().fold(Self::default(), Self::merge))
+    }
+
+    /// Loads a configuration file, parsed according to its extension (see [`ConfigFormat`])
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::load_layers(&[path])
+    }
+
+    /// Like [`Config::from_toml_layers`], with each layer read from a file in the format
+    /// given by its extension
+    pub fn load_layers<P: AsRef<Path>>(paths: &[P]) -> Result<Self> {
+        let layers = paths
+            .iter()
+            .map(|path| {
+                let path = path.as_ref();
+                let source = std::fs::read_to_string(path)?;
+                parse_layer(&source, ConfigFormat::from_path(path))
+                    .map_err(|e| match e {
+                        Error::Config(message) => Error::Config(format!("{}: {}", path.display(), message)),
+                        e => e,
+                    })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(layers.into_iter().fold(Self::default(), Self::merge))
+    }
+
+    /// Combines this configuration with a later layer, field by field: each field `other`
+    /// sets replaces the current value, and the fields it leaves unset are kept. Nested
+    /// sections such as `[ai.classification]` are combined the same way.
+    pub fn merge(mut self, other: ConfigLayer) -> Self {
+        self.ai.temperature_set |= other.ai.temperature.is_some();
+        self.ai.max_tokens_set |= other.ai.max_tokens.is_some();
+        self.ai.stop_sequences_set |= other.ai.stop_sequences.is_some();
+        Self {
+            ai: self.ai.merge(other.ai),
+            git: self.git.merge(other.git),
+            commit: self.commit.merge(other.commit),
+        }
+    }
+
+    /// Location of the global configuration applied below the project's, if a home or
+    /// config directory is known: `$XDG_CONFIG_HOME/commit-sage/config.toml` (or `~/.config/...`)
+    pub fn global_path() -> Option<PathBuf> {
+        Some(config_dir()?.join("config.toml"))
+    }
+
+    /// Checks settings that would make every generation fail. A `max_tokens` below
+    /// [`MIN_MAX_TOKENS`] only warns for a subject alone, but is an error when a body is
+    /// requested. Every `ci_paths` entry must be a valid glob.
+    pub fn validate(&self) -> Result<()> {
+        for pattern in &self.ai.classification.ci_paths {
+            glob::Pattern::new(pattern)
+                .map_err(|e| Error::Config(format!("invalid ci_paths glob '{}': {}", pattern, e)))?;
+        }
+        if self.ai.max_tokens < MIN_MAX_TOKENS {
+            if self.ai.include_body {
+                return Err(Error::Config(format!(
+                    "max_tokens is {}, too few for a subject and body; raise it (bodies get {} by default)",
+                    self.ai.max_tokens, BODY_MAX_TOKENS
+                )));
+            }
+            tracing::warn!(
+                "max_tokens is {}, messages will likely be cut off mid-word; use at least {}",
+                self.ai.max_tokens, MIN_MAX_TOKENS
+            );
+        }
+        Ok(())
+    }
+
+    /// Overrides settings from `COMMIT_SAGE_MODEL`, `COMMIT_SAGE_TEMPERATURE` and
+    /// `COMMIT_SAGE_MAX_TOKENS`, for environments where mounting a config file is awkward
+    pub fn apply_env_overrides(&mut self) -> Result<()> {
+        self.apply_overrides_from(|name| std::env::var(name).ok())
+    }
+
+    /// Like `apply_env_overrides`, with the variables read through `lookup`
+    pub fn apply_overrides_from(&mut self, lookup: impl Fn(&str) -> Option<String>) -> Result<()> {
+        if let Some(model) = lookup(ENV_MODEL) {
+            self.ai.model = model;
+        }
+        if let Some(temperature) = parse_env(&lookup, ENV_TEMPERATURE)? {
+            self.ai.temperature = temperature;
+            self.ai.temperature_set = true;
+        }
+        if let Some(max_tokens) = parse_env(&lookup, ENV_MAX_TOKENS)? {
+            self.ai.max_tokens = max_tokens;
+            self.ai.max_tokens_set = true;
+        }
+        Ok(())
+    }
+}
+
+/// Format of a configuration file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+    /// Requires the `yaml` cargo feature
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Detects the format from the extension (`.json`, `.yaml`/`.yml`), defaulting to TOML
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase).as_deref() {
+            Some("json") => Self::Json,
+            Some("yaml" | "yml") => Self::Yaml,
+            _ => Self::Toml,
+        }
+    }
+}
+
+/// Parses one configuration layer. Keys it leaves out are unset, unknown keys are errors.
+fn parse_layer(source: &str, format: ConfigFormat) -> Result<ConfigLayer> {
+    match format {
+        ConfigFormat::Toml => parse_toml(source),
+        ConfigFormat::Json => serde_json::from_str(source)
+            .map_err(|e| Error::Config(format!("line {}, column {}: {}", e.line(), e.column(), e))),
+        #[cfg(feature = "yaml")]
+        ConfigFormat::Yaml => serde_yaml::from_str(source).map_err(|e| Error::Config(e.to_string())),
+        #[cfg(not(feature = "yaml"))]
+        ConfigFormat::Yaml => Err(Error::Config(
+            "YAML configuration requires building with the `yaml` feature".to_string(),
+        )),
+    }
+}
+
+/// Per-user choices remembered between runs, such as the model picked on first run.
+/// Stored in `$XDG_CONFIG_HOME/commit-sage/settings.toml` (or `~/.config/...`).
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct UserSettings {
+    /// Model used when no config file, environment variable or flag sets one
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+impl UserSettings {
+    /// Location of the settings file, if a home or config directory is known
+    pub fn path() -> Option<PathBuf> {
+        Some(config_dir()?.join("settings.toml"))
+    }
+
+    /// Loads the settings, or empty settings if the file doesn't exist yet
+    pub fn load() -> Result<Self> {
+        match Self::path() {
+            Some(path) if path.is_file() => parse_toml(&std::fs::read_to_string(path)?),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    /// Writes the settings, creating the directory if needed
+    pub fn save(&self) -> Result<PathBuf> {
+        let path = Self::path().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "no home directory to store settings in")
+        })?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, toml::to_string(self)?)?;
+        Ok(path)
+    }
+}
+
+/// Per-user `commit-sage` directory under `$XDG_CONFIG_HOME`, or `~/.config`
+fn config_dir() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("commit-sage"))
+}
+
+/// Deserializes TOML, turning errors into messages that point at the offending spot
+fn parse_toml<T: serde::de::DeserializeOwned>(source: &str) -> Result<T> {
+    toml::from_str(source).map_err(|e| Error::Config(describe_toml_error(source, &e)))
+}
+
+/// Formats a TOML error as `line L, column C (field `key`): message`
+fn describe_toml_error(source: &str, error: &toml::de::Error) -> String {
+    let message = error.message().trim();
+    let Some(span) = error.span() else {
+        return message.to_string();
+    };
+    let before = &source[..span.start.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+
+    // The key on the offending line, unless the error points at a table header
+    let line_text = source.lines().nth(line - 1).unwrap_or("").trim();
+    let field = line_text
+        .split_once('=')
+        .map(|(key, _)| key.trim().trim_matches('"'))
+        .filter(|key| !key.is_empty() && !line_text.starts_with('['));
+
+    match field {
+        Some(field) => format!("line {}, column {} (field `{}`): {}", line, column, field, message),
+        None => format!("line {}, column {}: {}", line, column, message),
+    }
+}
+
+fn parse_env<T: std::str::FromStr>(lookup: impl Fn(&str) -> Option<String>, name: &str) -> Result<Option<T>> {
+    match lookup(name) {
+        Some(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| Error::InvalidEnvVar { name: name.to_string(), value }),
+        None => Ok(None),
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AiConfig {
+    /// The API provider to use
+    #[serde(default)]
+    pub provider: Provider,
+    /// Base URL of the Together.ai API, e.g. a regional endpoint or a proxy
+    #[serde(default = "default_api_base_url")]
+    pub api_base_url: String,
     /// The AI model to use
     pub model: String,
     /// Temperature for model output (0.0 to 1.0)
     pub temperature: f32,
+    /// Whether a configuration file or `COMMIT_SAGE_TEMPERATURE` set `temperature`, which
+    /// then replaces the provider's default
+    #[serde(skip)]
+    pub temperature_set: bool,
     /// Maximum tokens in the response
     pub max_tokens: u32,
+    /// Whether a configuration file or `COMMIT_SAGE_MAX_TOKENS` set `max_tokens`, which is
+    /// then kept as is rather than raised for a body or replaced by the provider's default
+    #[serde(skip)]
+    pub max_tokens_set: bool,
     /// Stop sequences for the model
     pub stop_sequences: Vec<String>,
+    /// Whether a configuration file set `stop_sequences`, which then replace the
+    /// provider's default
+    #[serde(skip)]
+    pub stop_sequences_set: bool,
     /// System prompt for the AI
     pub system_prompt: String,
+    /// Text prepended to the system prompt, e.g. a persona line
+    #[serde(default)]
+    pub system_prompt_prefix: Option<String>,
+    /// Text appended to the system prompt, e.g. the team's list of scopes
+    #[serde(default)]
+    pub system_prompt_suffix: Option<String>,
     /// User prompt template
     pub user_prompt_template: String,
+    /// Extra HTTP headers sent with every API request
+    #[serde(default)]
+    pub extra_headers: HashMap<String, String>,
+    /// Extra fields for the request body, e.g. `top_p` or `repetition_penalty`. Fields the
+    /// client sets itself, like `temperature` or `max_tokens`, take precedence.
+    #[serde(default)]
+    pub extra_params: serde_json::Map<String, serde_json::Value>,
+    /// Whether to drop comment-only line changes from the diff sent to the model
+    #[serde(default)]
+    pub strip_comments: bool,
+    /// Verbosity of the generated message
+    #[serde(default)]
+    pub length: MessageLength,
+    /// Whether to ask for a bullet-point body below the subject
+    #[serde(default)]
+    pub include_body: bool,
+    /// Prompt format expected by the model
+    #[serde(default)]
+    pub prompt_style: PromptStyle,
+    /// Token budget for the diffs included in the prompt
+    #[serde(default = "default_max_diff_tokens")]
+    pub max_diff_tokens: usize,
+    /// Whether to request a JSON object (type, scope, description) and assemble the
+    /// message from it, instead of parsing free text (chat prompt style only)
+    #[serde(default)]
+    pub structured_output: bool,
+    /// Whether to list every changed file with its status in the prompt, so files cut
+    /// from a truncated diff are still seen
+    #[serde(default)]
+    pub include_file_list: bool,
+    /// How many times a message is regenerated because its format or type is wrong,
+    /// separate from the retries of failed requests
+    #[serde(default = "default_max_validation_retries")]
+    pub max_validation_retries: u32,
+    /// Whether a diff over `max_diff_tokens` is condensed by first summarizing its largest
+    /// files in a sentence each, instead of only being truncated
+    #[serde(default)]
+    pub hierarchical_summary: bool,
+    /// Thresholds of the heuristic diff classification that suggests a commit type
+    #[serde(default)]
+    pub classification: ClassificationThresholds,
+}
+
+/// Limits and paths used by `CommitContext` to classify a diff
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ClassificationThresholds {
+    /// Diffs with more added lines are a large feature implementation
+    pub large_additions_threshold: usize,
+    /// Diffs with more new files are a large feature (or an initial setup with a manifest)
+    pub many_files_threshold: usize,
+    /// Diffs deleting more than this many times the lines they add are a refactoring
+    pub refactor_deletion_ratio: f32,
+    /// Glob patterns of CI configuration; diffs touching only these are a CI change
+    pub ci_paths: Vec<String>,
+}
+
+impl Default for ClassificationThresholds {
+    fn default() -> Self {
+        Self {
+            large_additions_threshold: 100,
+            many_files_threshold: 5,
+            refactor_deletion_ratio: 2.0,
+            ci_paths: [
+                ".github/workflows/*",
+                ".github/actions/*",
+                ".gitlab-ci.yml",
+                ".gitlab-ci/*",
+                ".circleci/*",
+                ".travis.yml",
+                ".buildkite/*",
+                "azure-pipelines.yml",
+                "Jenkinsfile",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+fn default_api_base_url() -> String {
+    crate::protocol::TOGETHER_API_BASE_URL.to_string()
+}
+
+fn default_max_diff_tokens() -> usize {
+    8000
+}
+
+fn default_max_validation_retries() -> u32 {
+    2
+}
+
+fn default_recommended_length() -> usize {
+    50
+}
+
+/// Default `max_tokens` when a body is requested, so it isn't silently truncated
+pub const BODY_MAX_TOKENS: u32 = 400;
+
+impl AiConfig {
+    /// Replaces the temperature, `max_tokens` and stop sequences with the defaults of
+    /// the selected provider, each unless a file or the environment set it. Flags are
+    /// applied afterwards.
+    pub fn apply_provider_defaults(&mut self) {
+        use crate::protocol::{HuggingFaceProvider, ModelProvider, TogetherAiProvider};
+        let defaults = match self.provider {
+            Provider::Together => TogetherAiProvider::new(String::new(), self.model.clone()).default_config(),
+            Provider::HuggingFace => HuggingFaceProvider::new(String::new(), self.model.clone()).default_config(),
+        };
+        if !self.temperature_set {
+            self.temperature = defaults.temperature;
+        }
+        if !self.max_tokens_set {
+            self.max_tokens = defaults.max_tokens;
+        }
+        if !self.stop_sequences_set {
+            self.stop_sequences = defaults.stop_sequences;
+        }
+    }
+
+    /// Raises `max_tokens` to [`BODY_MAX_TOKENS`] when a body is requested, unless a file or
+    /// the environment set it (`max_tokens_set`). Callers also skip this when a flag sets it.
+    pub fn reserve_body_tokens(&mut self) {
+        if self.include_body && !self.max_tokens_set {
+            self.max_tokens = self.max_tokens.max(BODY_MAX_TOKENS);
+        }
+    }
+}
+
+/// Hosted API used to generate messages
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    /// Together.ai
+    #[default]
+    Together,
+    /// Hugging Face Inference API
+    #[serde(alias = "hf")]
+    HuggingFace,
+}
+
+impl Provider {
+    /// Environment variable holding this provider's API key
+    pub fn api_key_env(self) -> &'static str {
+        match self {
+            Provider::Together => "TOGETHER_API_KEY",
+            Provider::HuggingFace => "HF_TOKEN",
+        }
+    }
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Provider::Together => "Together.ai",
+            Provider::HuggingFace => "Hugging Face",
+        })
+    }
+}
+
+/// How the prompt is sent to the model
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptStyle {
+    /// A messages array sent to the chat completions endpoint
+    #[default]
+    Chat,
+    /// A single `[INST] ... [/INST]` prompt sent to the completions endpoint
+    MistralInstruct,
+    /// A `[INST] <<SYS>> ... <</SYS>> ... [/INST]` prompt sent to the completions endpoint
+    Llama2,
+}
+
+/// Where the issue key from the branch name goes in the message
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TicketPlacement {
+    /// Before the subject, e.g. `PROJ-123 feat: ...` for Jira Smart Commits
+    #[default]
+    Prefix,
+    /// On its own line at the end of the message
+    Footer,
+}
+
+/// Algorithm used to compute the diff hunks sent to the model
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffAlgorithm {
+    /// Git's default
+    #[default]
+    Myers,
+    /// Myers, spending extra time to find the smallest diff
+    Minimal,
+    /// Matches unique lines first, often clearer for moved or refactored code
+    Patience,
+    /// Not implemented by libgit2, falls back to patience (histogram extends it)
+    Histogram,
+}
+
+/// How verbose the generated commit message should be
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageLength {
+    /// A terse subject line only
+    Short,
+    /// A subject line only
+    #[default]
+    Normal,
+    /// A subject line followed by a bullet-point body
+    Detailed,
+}
+
+impl MessageLength {
+    /// Adjusts body generation and the token budget for this verbosity. Room for a body
+    /// is made separately, see [`AiConfig::reserve_body_tokens`].
+    pub fn apply(self, config: &mut AiConfig) {
+        match self {
+            MessageLength::Short => {
+                config.include_body = false;
+                config.max_tokens = config.max_tokens.min(40);
+            }
+            MessageLength::Normal => {}
+            MessageLength::Detailed => config.include_body = true,
+        }
+        if config.include_body {
+            // A newline stop sequence would cut the message off after the subject
+            config.stop_sequences.retain(|s| s != "\n");
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -32,39 +520,150 @@ pub struct GitConfig {
     pub include_untracked: bool,
     /// Whether to show the diff before generating commit message
     pub show_diff: bool,
+    /// Glob patterns of paths left out of the diff sent to the model
+    #[serde(default)]
+    pub exclude_paths: Vec<String>,
+    /// Whether to describe and commit only what is already staged in the index
+    #[serde(default)]
+    pub staged_only: bool,
+    /// Whether to stage all changes before committing; when false the existing index is committed
+    #[serde(default = "default_auto_stage")]
+    pub auto_stage: bool,
+    /// Glob patterns of generated or vendored paths whose content is left out of the diff
+    /// sent to the model, besides files marked `linguist-generated` in `.gitattributes`
+    #[serde(default = "default_generated_paths")]
+    pub generated_paths: Vec<String>,
+    /// Diff algorithm used for the diffs sent to the model
+    #[serde(default)]
+    pub diff_algorithm: DiffAlgorithm,
+}
+
+fn default_generated_paths() -> Vec<String> {
+    ["vendor/*", "node_modules/*", "target/*"].map(String::from).to_vec()
+}
+
+fn default_auto_stage() -> bool {
+    true
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CommitConfig {
     /// List of allowed commit types
     pub allowed_types: Vec<String>,
+    /// List of allowed scopes (empty allows any scope)
+    #[serde(default)]
+    pub allowed_scopes: Vec<String>,
+    /// Separator of nested scopes like `api/auth`, whose parts are then checked one by
+    /// one. Unset, a scope is taken as a whole, whatever characters it contains.
+    #[serde(default)]
+    pub scope_separator: Option<String>,
     /// Maximum length of commit message
     pub max_length: usize,
+    /// Subject length limits for specific commit types, overriding `max_length`
+    #[serde(default)]
+    pub max_length_by_type: HashMap<String, usize>,
+    /// Subject length git convention recommends; longer subjects get a warning
+    #[serde(default = "default_recommended_length")]
+    pub recommended_length: usize,
+    /// Whether a subject over `recommended_length` fails validation instead of warning
+    #[serde(default)]
+    pub strict_length: bool,
     /// Whether to automatically commit after generating message
     pub auto_commit: bool,
     /// Whether to verify commit message format
     pub verify_format: bool,
     /// Whether to require user confirmation before committing
     pub require_confirmation: bool,
+    /// Whether an empty answer to the confirmation prompt commits (`[Y/n]`)
+    #[serde(default)]
+    pub confirm_default: bool,
+    /// Whether to accept any valid type from the model; when false, a message whose type
+    /// differs from the one suggested by the diff classification is regenerated once
+    #[serde(default = "default_trust_model_type")]
+    pub trust_model_type: bool,
+    /// Whether to add `Closes #N` footers for issue keywords found in the diff
+    #[serde(default)]
+    pub detect_issue_keywords: bool,
+    /// Whether to ask for a body that explains why the change was made, not only what changed
+    #[serde(default)]
+    pub explain_why: bool,
+    /// Whether to regenerate messages whose description isn't in imperative mood
+    #[serde(default)]
+    pub enforce_imperative: bool,
+    /// Whether a breaking change marked with `!` must also have a `BREAKING CHANGE:`
+    /// footer. The spec allows `!` alone, so this is off by default.
+    #[serde(default)]
+    pub require_breaking_footer: bool,
+    /// Whether messages may start with a gitmoji such as `🐛 fix: x`. When off, a leading
+    /// emoji the model adds anyway is stripped before validation.
+    #[serde(default)]
+    pub gitmoji: bool,
+    /// Whether to list the repository's modules and directories in the prompt, so the
+    /// model picks scopes that exist
+    #[serde(default)]
+    pub include_tree_context: bool,
+    /// Whether to lowercase the first letter of generated descriptions and drop a trailing
+    /// period, as the spec asks. Acronyms and identifiers are left as they are.
+    #[serde(default = "default_lowercase_description")]
+    pub lowercase_description: bool,
+    /// Message used by `--wip` checkpoint commits, which skip the model
+    #[serde(default = "default_wip_message")]
+    pub wip_message: String,
+    /// Diffs with fewer changed lines get a local message without calling the API (0 disables)
+    #[serde(default)]
+    pub min_diff_lines: usize,
+    /// Pattern the whole message must match, e.g. a required ticket reference
+    #[serde(default)]
+    pub custom_regex: Option<String>,
+    /// Whether to add the issue key found in the current branch name to the message
+    #[serde(default)]
+    pub ticket_from_branch: bool,
+    /// Pattern of the issue key in branch names
+    #[serde(default = "default_ticket_pattern")]
+    pub ticket_pattern: String,
+    /// Where the issue key goes in the message
+    #[serde(default)]
+    pub ticket_placement: TicketPlacement,
+    /// Column at which body lines are hard-wrapped
+    #[serde(default = "default_body_wrap_width")]
+    pub body_wrap_width: usize,
+    /// Maximum number of body lines kept after wrapping (unlimited if unset)
+    #[serde(default)]
+    pub max_body_lines: Option<usize>,
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            ai: AiConfig::default(),
-            git: GitConfig::default(),
-            commit: CommitConfig::default(),
-        }
-    }
+fn default_wip_message() -> String {
+    "chore: wip".to_string()
+}
+
+fn default_trust_model_type() -> bool {
+    true
+}
+
+fn default_lowercase_description() -> bool {
+    true
+}
+
+fn default_body_wrap_width() -> usize {
+    72
+}
+
+fn default_ticket_pattern() -> String {
+    crate::ticket::DEFAULT_TICKET_PATTERN.to_string()
 }
 
 impl Default for AiConfig {
     fn default() -> Self {
         Self {
+            provider: Provider::Together,
+            api_base_url: default_api_base_url(),
             model: "mistralai/Mixtral-8x7B-Instruct-v0.1".to_string(),
             temperature: 0.3,
+            temperature_set: false,
             max_tokens: 100,
+            max_tokens_set: false,
             stop_sequences: vec!["\n".to_string()],
+            stop_sequences_set: false,
             system_prompt: "You are a highly skilled developer who writes perfect conventional commit messages. \
                 Your task is to analyze git diffs and generate commit messages that strictly follow the Conventional Commits specification.\n\n\
                 COMMIT FORMAT RULES:\n\
@@ -107,6 +706,8 @@ impl Default for AiConfig {
                    - Choose most important components\n\
                    - Prioritize key technologies\n\
                    - Select defining patterns".to_string(),
+            system_prompt_prefix: None,
+            system_prompt_suffix: None,
             user_prompt_template: "Generate a conventional commit message for the following git diff.\n\
                 The message MUST strictly follow the conventional commit format rules specified above.\n\
                 This is a {}, so ensure the message reflects the scope of changes.\n\
@@ -114,6 +715,18 @@ impl Default for AiConfig {
                 Validate your message against the examples and rules before returning it.\n\
                 Only return the commit message, nothing else.\n\n\
                 Diff:\n{}".to_string(),
+            extra_headers: HashMap::new(),
+            extra_params: serde_json::Map::new(),
+            strip_comments: false,
+            length: MessageLength::Normal,
+            include_body: false,
+            prompt_style: PromptStyle::Chat,
+            max_diff_tokens: default_max_diff_tokens(),
+            structured_output: false,
+            include_file_list: false,
+            max_validation_retries: default_max_validation_retries(),
+            hierarchical_summary: false,
+            classification: ClassificationThresholds::default(),
         }
     }
 }
@@ -124,6 +737,11 @@ impl Default for GitConfig {
             repo_path: PathBuf::from("."),
             include_untracked: true,
             show_diff: false,
+            exclude_paths: Vec::new(),
+            staged_only: false,
+            auto_stage: true,
+            generated_paths: default_generated_paths(),
+            diff_algorithm: DiffAlgorithm::Myers,
         }
     }
 }
@@ -144,18 +762,422 @@ impl Default for CommitConfig {
                 "chore".to_string(),
                 "revert".to_string(),
             ],
+            allowed_scopes: Vec::new(),
+            scope_separator: None,
             max_length: 72,
+            max_length_by_type: HashMap::new(),
+            recommended_length: default_recommended_length(),
+            strict_length: false,
             auto_commit: false,
             verify_format: true,
             require_confirmation: true,
+            trust_model_type: true,
+            confirm_default: false,
+            detect_issue_keywords: false,
+            explain_why: false,
+            enforce_imperative: false,
+            require_breaking_footer: false,
+            gitmoji: false,
+            include_tree_context: false,
+            lowercase_description: true,
+            wip_message: default_wip_message(),
+            min_diff_lines: 0,
+            custom_regex: None,
+            ticket_from_branch: false,
+            ticket_pattern: default_ticket_pattern(),
+            ticket_placement: TicketPlacement::Prefix,
+            body_wrap_width: default_body_wrap_width(),
+            max_body_lines: None,
+        }
+    }
+}
+
+/// Declares the partial form of a configuration section, as read from one layer with
+/// every field optional, and the `merge` that applies it on top of the full section.
+/// `optional` lists fields that are already an `Option` in the section, `nested` the
+/// sections merged recursively.
+macro_rules! config_layer {
+    (
+        $(#[$meta:meta])*
+        $layer:ident => $section:ident {
+            $($field:ident: $ty:ty,)*
+        }
+        $(optional { $($optional:ident: $optional_ty:ty,)* })?
+        $(nested { $($nested:ident: $nested_ty:ty,)* })?
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Default, Deserialize)]
+        #[serde(default, deny_unknown_fields)]
+        pub struct $layer {
+            $(pub $field: Option<$ty>,)*
+            $($(pub $optional: Option<$optional_ty>,)*)?
+            $($(pub $nested: $nested_ty,)*)?
         }
+
+        impl $section {
+            /// Replaces each field `layer` sets and keeps the others
+            pub fn merge(mut self, layer: $layer) -> Self {
+                $(if let Some(value) = layer.$field {
+                    self.$field = value;
+                })*
+                $($(if let Some(value) = layer.$optional {
+                    self.$optional = Some(value);
+                })*)?
+                $($(self.$nested = self.$nested.merge(layer.$nested);)*)?
+                self
+            }
+        }
+    };
+}
+
+/// One configuration file, with unset fields left `None`, see [`Config::merge`]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigLayer {
+    pub ai: AiLayer,
+    pub git: GitLayer,
+    pub commit: CommitLayer,
+}
+
+config_layer! {
+    /// The `[ai]` section of a [`ConfigLayer`]
+    AiLayer => AiConfig {
+        provider: Provider,
+        api_base_url: String,
+        model: String,
+        temperature: f32,
+        max_tokens: u32,
+        stop_sequences: Vec<String>,
+        system_prompt: String,
+        user_prompt_template: String,
+        extra_headers: HashMap<String, String>,
+        extra_params: serde_json::Map<String, serde_json::Value>,
+        strip_comments: bool,
+        length: MessageLength,
+        include_body: bool,
+        prompt_style: PromptStyle,
+        max_diff_tokens: usize,
+        structured_output: bool,
+        include_file_list: bool,
+        max_validation_retries: u32,
+        hierarchical_summary: bool,
+    }
+    optional {
+        system_prompt_prefix: String,
+        system_prompt_suffix: String,
+    }
+    nested {
+        classification: ClassificationLayer,
     }
 }
 
-pub const AVAILABLE_MODELS: &[(&str, &str)] = &[
-    ("mistralai/Mixtral-8x7B-Instruct-v0.1", "Best overall performance, recommended default"),
-    ("meta-llama/Llama-2-70b-chat-hf", "Excellent for detailed analysis"),
-    ("mistralai/Mistral-7B-Instruct-v0.2", "Fast and efficient"),
-    ("NousResearch/Nous-Hermes-2-Mixtral-8x7B-DPO", "Optimized for coding tasks"),
-    ("openchat/openchat-3.5-0106", "Good balance of performance and speed"),
-]; 
\ No newline at end of file
+config_layer! {
+    /// The `[ai.classification]` section of a [`ConfigLayer`]
+    ClassificationLayer => ClassificationThresholds {
+        large_additions_threshold: usize,
+        many_files_threshold: usize,
+        refactor_deletion_ratio: f32,
+        ci_paths: Vec<String>,
+    }
+}
+
+config_layer! {
+    /// The `[git]` section of a [`ConfigLayer`]
+    GitLayer => GitConfig {
+        repo_path: PathBuf,
+        include_untracked: bool,
+        show_diff: bool,
+        exclude_paths: Vec<String>,
+        staged_only: bool,
+        auto_stage: bool,
+        generated_paths: Vec<String>,
+        diff_algorithm: DiffAlgorithm,
+    }
+}
+
+config_layer! {
+    /// The `[commit]` section of a [`ConfigLayer`]
+    CommitLayer => CommitConfig {
+        allowed_types: Vec<String>,
+        allowed_scopes: Vec<String>,
+        max_length: usize,
+        max_length_by_type: HashMap<String, usize>,
+        recommended_length: usize,
+        strict_length: bool,
+        auto_commit: bool,
+        verify_format: bool,
+        require_confirmation: bool,
+        confirm_default: bool,
+        trust_model_type: bool,
+        detect_issue_keywords: bool,
+        explain_why: bool,
+        enforce_imperative: bool,
+        require_breaking_footer: bool,
+        gitmoji: bool,
+        include_tree_context: bool,
+        lowercase_description: bool,
+        wip_message: String,
+        min_diff_lines: usize,
+        ticket_from_branch: bool,
+        ticket_pattern: String,
+        ticket_placement: TicketPlacement,
+        body_wrap_width: usize,
+    }
+    optional {
+        scope_separator: String,
+        custom_regex: String,
+        max_body_lines: usize,
+    }
+}
+
+/// Capability tag of models suited to code, such as commit message generation
+pub const TAG_CODING: &str = "coding";
+/// Capability tag of chat-tuned models
+pub const TAG_CHAT: &str = "chat";
+
+/// Known models with a description and capability tags
+pub const AVAILABLE_MODELS: &[(&str, &str, &[&str])] = &[
+    ("mistralai/Mixtral-8x7B-Instruct-v0.1", "Best overall performance, recommended default", &[TAG_CHAT, TAG_CODING]),
+    ("meta-llama/Llama-2-70b-chat-hf", "Excellent for detailed analysis", &[TAG_CHAT]),
+    ("mistralai/Mistral-7B-Instruct-v0.2", "Fast and efficient", &[TAG_CHAT]),
+    ("NousResearch/Nous-Hermes-2-Mixtral-8x7B-DPO", "Optimized for coding tasks", &[TAG_CHAT, TAG_CODING]),
+    ("openchat/openchat-3.5-0106", "Good balance of performance and speed", &[TAG_CHAT]),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_from_toml_str_reports_field_type_error() {
+        let source = "[ai]\nmodel = \"m\"\ntemperature = \"hot\"\n";
+        let Err(Error::Config(message)) = Config::from_toml_str(source) else {
+            panic!("expected a configuration error");
+        };
+        assert!(message.starts_with("line 3, column 15 (field `temperature`): invalid type"), "{}", message);
+    }
+
+    #[test]
+    fn test_from_toml_str_reports_syntax_error() {
+        let source = "[ai]\nmodel \"m\"\n";
+        let Err(Error::Config(message)) = Config::from_toml_str(source) else {
+            panic!("expected a configuration error");
+        };
+        assert!(message.starts_with("line 2, column 7"), "{}", message);
+    }
+
+    #[test]
+    fn test_from_toml_str_reports_unknown_variant() {
+        let source = "[commit]\nticket_placement = \"middle\"\n";
+        let Err(Error::Config(message)) = Config::from_toml_str(source) else {
+            panic!("expected a configuration error");
+        };
+        assert!(message.contains("(field `ticket_placement`): unknown variant `middle`"), "{}", message);
+    }
+
+    #[test]
+    fn test_later_layers_take_precedence() {
+        let global = "[ai]\nmodel = \"global-model\"\ntemperature = 0.1\n\n[ai.classification]\nmany_files_threshold = 9\n";
+        let project = "[ai]\ntemperature = 0.7\n\n[ai.classification]\nlarge_additions_threshold = 50\n\n[commit]\nmax_length = 50\n";
+        let config = Config::from_toml_layers(&[global, project]).unwrap();
+
+        assert_eq!(config.ai.model, "global-model");
+        assert_eq!(config.ai.temperature, 0.7);
+        assert_eq!(config.ai.classification.many_files_threshold, 9);
+        assert_eq!(config.ai.classification.large_additions_threshold, 50);
+        assert_eq!(config.commit.max_length, 50);
+        assert_eq!(config.commit.allowed_types, CommitConfig::default().allowed_types);
+        assert!(config.git.auto_stage);
+    }
+
+    #[test]
+    fn test_layers_merge_per_key() {
+        let global = "[ai]\nmodel = \"g\"\ntemperature = 0.1\nstop_sequences = [\"a\", \"b\"]\n";
+        let project = "[ai]\nmodel = \"p\"\nstop_sequences = [\"c\"]\n";
+        let config = Config::from_toml_layers(&[global, project]).unwrap();
+
+        assert_eq!(config.ai.model, "p");
+        assert_eq!(config.ai.temperature, 0.1);
+        assert_eq!(config.ai.stop_sequences, vec!["c".to_string()]);
+        assert_eq!(config.ai.max_tokens, AiConfig::default().max_tokens);
+    }
+
+    #[test]
+    fn test_merge_precedence() {
+        let global = ConfigLayer {
+            ai: AiLayer { model: Some("global".to_string()), temperature: Some(0.1), ..AiLayer::default() },
+            commit: CommitLayer { custom_regex: Some("^x".to_string()), ..CommitLayer::default() },
+            ..ConfigLayer::default()
+        };
+        let project = ConfigLayer {
+            ai: AiLayer {
+                model: Some("project".to_string()),
+                classification: ClassificationLayer { many_files_threshold: Some(9), ..ClassificationLayer::default() },
+                ..AiLayer::default()
+            },
+            ..ConfigLayer::default()
+        };
+        let config = Config::default().merge(global).merge(project);
+
+        assert_eq!(config.ai.model, "project");
+        assert_eq!(config.ai.temperature, 0.1);
+        assert_eq!(config.ai.max_tokens, AiConfig::default().max_tokens);
+        assert!(!config.ai.max_tokens_set);
+        assert_eq!(config.ai.classification.many_files_threshold, 9);
+        assert_eq!(config.ai.classification.large_additions_threshold, 100);
+        assert_eq!(config.commit.custom_regex.as_deref(), Some("^x"));
+        assert_eq!(config.commit.max_length, CommitConfig::default().max_length);
+    }
+
+    #[test]
+    fn test_provider_defaults_fill_unset_generation_settings() {
+        let mut config = Config::from_toml_str("[ai]\nprovider = \"huggingface\"\n").unwrap();
+        config.ai.apply_provider_defaults();
+        assert_eq!(config.ai.max_tokens, 150);
+
+        let mut config = Config::from_toml_str("[ai]\nprovider = \"huggingface\"\nmax_tokens = 60\ntemperature = 0.9\n").unwrap();
+        config.ai.apply_provider_defaults();
+        assert_eq!(config.ai.max_tokens, 60);
+        assert_eq!(config.ai.temperature, 0.9);
+    }
+
+    #[test]
+    fn test_unknown_key_is_an_error() {
+        let Err(Error::Config(message)) = Config::from_toml_str("[ai]\nmodle = \"m\"\n") else {
+            panic!("expected a configuration error");
+        };
+        assert!(message.contains("unknown field `modle`"), "{}", message);
+    }
+
+    #[test]
+    fn test_example_config_loads() {
+        Config::from_toml_str(include_str!("../config.example.toml")).unwrap();
+    }
+
+    #[test]
+    fn test_layer_errors_point_into_the_layer() {
+        let global = "[ai]\nmodel = \"m\"\n";
+        let project = "[commit]\nmax_length = \"long\"\n";
+        let Err(Error::Config(message)) = Config::from_toml_layers(&[global, project]) else {
+            panic!("expected a configuration error");
+        };
+        assert!(message.starts_with("line 2, column 14 (field `max_length`)"), "{}", message);
+    }
+
+    #[test_case::test_case("commit-sage.toml", ConfigFormat::Toml ; "toml")]
+    #[test_case::test_case(".commit-sage.json", ConfigFormat::Json ; "json")]
+    #[test_case::test_case(".commit-sage.yaml", ConfigFormat::Yaml ; "yaml")]
+    #[test_case::test_case("config/commit-sage.YML", ConfigFormat::Yaml ; "uppercase yml")]
+    #[test_case::test_case("commit-sage", ConfigFormat::Toml ; "no extension")]
+    fn test_config_format_from_path(path: &str, expected: ConfigFormat) {
+        assert_eq!(ConfigFormat::from_path(Path::new(path)), expected);
+    }
+
+    #[test]
+    fn test_load_layers_mixes_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        let global = dir.path().join("config.toml");
+        let project = dir.path().join(".commit-sage.json");
+        std::fs::write(&global, "[ai]\nmodel = \"global-model\"\ntemperature = 0.1\n").unwrap();
+        std::fs::write(&project, r#"{ "ai": { "temperature": 0.7 }, "commit": { "max_length": 50 } }"#).unwrap();
+
+        let config = Config::load_layers(&[&global, &project]).unwrap();
+        assert_eq!(config.ai.model, "global-model");
+        assert_eq!(config.ai.temperature, 0.7);
+        assert_eq!(config.commit.max_length, 50);
+
+        std::fs::write(&project, r#"{ "commit": { "max_length": "long" } }"#).unwrap();
+        let Err(Error::Config(message)) = Config::load(&project) else {
+            panic!("expected a configuration error");
+        };
+        assert!(message.starts_with(&format!("{}: line 1", project.display())), "{}", message);
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn test_load_yaml() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".commit-sage.yml");
+        std::fs::write(&path, "ai:\n  model: yaml-model\ncommit:\n  allowed_types: [feat, fix]\n").unwrap();
+
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.ai.model, "yaml-model");
+        assert_eq!(config.commit.allowed_types, vec!["feat", "fix"]);
+    }
+
+    #[test_case::test_case(10, false, true ; "small subject only warns")]
+    #[test_case::test_case(10, true, false ; "small with body is an error")]
+    #[test_case::test_case(MIN_MAX_TOKENS, true, true ; "floor is accepted")]
+    fn test_validate_max_tokens(max_tokens: u32, include_body: bool, valid: bool) {
+        let mut config = Config::default();
+        config.ai.max_tokens = max_tokens;
+        config.ai.include_body = include_body;
+        assert_eq!(config.validate().is_ok(), valid);
+    }
+
+    #[test]
+    fn test_invalid_ci_path_is_a_config_error() {
+        let mut config = Config::default();
+        config.ai.classification.ci_paths.push("ci/[".to_string());
+        let Err(Error::Config(message)) = config.validate() else {
+            panic!("expected a configuration error");
+        };
+        assert!(message.starts_with("invalid ci_paths glob 'ci/['"), "{}", message);
+    }
+
+    #[test]
+    fn test_detailed_length_reserves_body_tokens() {
+        let mut config = AiConfig::default();
+        MessageLength::Detailed.apply(&mut config);
+        assert_eq!(config.max_tokens, 100);
+        config.reserve_body_tokens();
+        assert_eq!(config.max_tokens, BODY_MAX_TOKENS);
+
+        let mut config = AiConfig::default();
+        config.reserve_body_tokens();
+        assert_eq!(config.max_tokens, 100);
+    }
+
+    #[test]
+    fn test_max_tokens_from_file_is_not_raised_for_a_body() {
+        let mut config = Config::from_toml_layers(&["[ai]\nmax_tokens = 150\ninclude_body = true\n"]).unwrap();
+        config.ai.reserve_body_tokens();
+        assert_eq!(config.ai.max_tokens, 150);
+
+        let mut config = Config::from_toml_layers(&["[ai]\ninclude_body = true\n"]).unwrap();
+        config.ai.reserve_body_tokens();
+        assert_eq!(config.ai.max_tokens, BODY_MAX_TOKENS);
+
+        let mut config = Config::from_toml_layers(&["[ai]\ninclude_body = true\n"]).unwrap();
+        config.apply_overrides_from(|name| (name == ENV_MAX_TOKENS).then(|| "120".to_string())).unwrap();
+        config.ai.reserve_body_tokens();
+        assert_eq!(config.ai.max_tokens, 120);
+    }
+
+    #[test]
+    fn test_provider_display() {
+        assert_eq!(Provider::Together.to_string(), "Together.ai");
+        assert_eq!(Provider::HuggingFace.to_string(), "Hugging Face");
+    }
+
+    #[test]
+    fn test_apply_env_overrides() {
+        let mut env = HashMap::from([
+            (ENV_MODEL, "mistralai/Mistral-7B-Instruct-v0.2"),
+            (ENV_TEMPERATURE, "0.7"),
+            (ENV_MAX_TOKENS, "250"),
+        ]);
+        let mut config = Config::default();
+        let result = config.apply_overrides_from(|name| env.get(name).map(|value| value.to_string()));
+
+        env.insert(ENV_MAX_TOKENS, "many");
+        let invalid = Config::default().apply_overrides_from(|name| env.get(name).map(|value| value.to_string()));
+
+        assert!(result.is_ok());
+        assert_eq!(config.ai.model, "mistralai/Mistral-7B-Instruct-v0.2");
+        assert_eq!(config.ai.temperature, 0.7);
+        assert_eq!(config.ai.max_tokens, 250);
+        assert!(matches!(invalid, Err(Error::InvalidEnvVar { name, .. }) if name == ENV_MAX_TOKENS));
+    }
+}
@@ -0,0 +1,278 @@
+use std::fmt;
+
+/// The parts of a Conventional Commits subject line, `type(scope)!: description`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedCommit {
+    pub commit_type: String,
+    pub scope: Option<String>,
+    pub breaking: bool,
+    pub description: String,
+}
+
+impl ParsedCommit {
+    /// The subject up to the colon, e.g. `feat(auth)!`
+    pub fn prefix(&self) -> String {
+        let scope = self.scope.as_ref().map(|s| format!("({})", s)).unwrap_or_default();
+        let breaking = if self.breaking { "!" } else { "" };
+        format!("{}{}{}", self.commit_type, scope, breaking)
+    }
+
+    /// The parts of a nested scope like `api/auth` split on `separator`, or the whole scope
+    /// when there is no separator. Empty without a scope.
+    pub fn scope_parts(&self, separator: Option<&str>) -> Vec<&str> {
+        match (self.scope.as_deref(), separator.filter(|s| !s.is_empty())) {
+            (Some(scope), Some(separator)) => scope.split(separator).collect(),
+            (Some(scope), None) => vec![scope],
+            (None, _) => Vec::new(),
+        }
+    }
+
+    /// The subject line rebuilt from the parts
+    pub fn subject(&self) -> String {
+        format!("{}: {}", self.prefix(), self.description)
+    }
+}
+
+/// Parses the subject line of a message. Returns `None` unless it is
+/// `type(scope)!: description` with a non-empty type and description, where the scope
+/// and `!` are optional but empty parentheses are malformed.
+pub fn parse_conventional(message: &str) -> Option<ParsedCommit> {
+    let subject = message.lines().next()?.trim();
+    let (type_part, description) = subject.split_once(": ")?;
+    let description = description.trim();
+    if description.is_empty() {
+        return None;
+    }
+
+    let breaking = type_part.ends_with('!');
+    let type_part = type_part.strip_suffix('!').unwrap_or(type_part);
+    let (commit_type, scope) = match type_part.split_once('(') {
+        Some((commit_type, rest)) => {
+            let scope = rest.strip_suffix(')')?;
+            if scope.trim().is_empty() || scope.contains(['(', ')']) {
+                return None;
+            }
+            (commit_type, Some(scope.to_string()))
+        }
+        None => (type_part, None),
+    };
+    if commit_type.is_empty() || !commit_type.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return None;
+    }
+
+    Some(ParsedCommit {
+        commit_type: commit_type.to_string(),
+        scope,
+        breaking,
+        description: description.to_string(),
+    })
+}
+
+/// The description of a `BREAKING CHANGE:` (or `BREAKING-CHANGE:`) footer, if the message
+/// has one. An empty description is returned as `Some("")`.
+pub fn breaking_change_footer(message: &str) -> Option<&str> {
+    message.lines().skip(1).find_map(|line| {
+        line.strip_prefix("BREAKING CHANGE:")
+            .or_else(|| line.strip_prefix("BREAKING-CHANGE:"))
+            .map(str::trim)
+    })
+}
+
+/// Adds the `!` breaking marker to a conventional subject, keeping the rest of the message
+pub fn mark_breaking(message: &str) -> String {
+    let Some(mut parsed) = parse_conventional(message) else {
+        return message.to_string();
+    };
+    parsed.breaking = true;
+    match message.split_once('\n') {
+        Some((_, rest)) => format!("{}\n{}", parsed.subject(), rest),
+        None => parsed.subject(),
+    }
+}
+
+/// A structural problem of a whole commit message, beyond its subject's format
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StructureViolation {
+    /// The subject ends with punctuation such as `.`
+    SubjectEndsWithPunctuation(char),
+    /// The body starts right below the subject
+    NoBlankLineAfterSubject,
+    /// More than one blank line separates the subject from the body
+    ExtraBlankLinesAfterSubject,
+    /// The footers at the end aren't set off from the body by a blank line
+    NoBlankLineBeforeFooters,
+}
+
+impl fmt::Display for StructureViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SubjectEndsWithPunctuation(c) => write!(f, "subject ends with '{}'", c),
+            Self::NoBlankLineAfterSubject => write!(f, "no blank line between subject and body"),
+            Self::ExtraBlankLinesAfterSubject => write!(f, "more than one blank line between subject and body"),
+            Self::NoBlankLineBeforeFooters => write!(f, "no blank line before the footers"),
+        }
+    }
+}
+
+/// A footer line: `Token: value`, `Token #value` or `BREAKING CHANGE: description`
+fn is_footer(line: &str) -> bool {
+    if line.starts_with("BREAKING CHANGE:") {
+        return true;
+    }
+    let token_end = line.find(" #").unwrap_or(line.len());
+    let (token, rest) = line.split_at(token_end);
+    crate::trailer::split_trailer(line).is_some()
+        || (!rest.is_empty() && !token.is_empty() && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+}
+
+/// Checks the layout of a whole message: the subject doesn't end with punctuation, a
+/// body is separated from it by exactly one blank line, and footers at the end follow
+/// a blank line
+pub fn structure_violations(message: &str) -> Vec<StructureViolation> {
+    let lines: Vec<&str> = message.trim_end().lines().collect();
+    let mut violations = Vec::new();
+    let Some(subject) = lines.first() else {
+        return violations;
+    };
+    if let Some(c) = subject.trim_end().chars().last().filter(|c| ".,;:!?".contains(*c)) {
+        violations.push(StructureViolation::SubjectEndsWithPunctuation(c));
+    }
+    if lines.len() == 1 {
+        return violations;
+    }
+
+    let blank_lines = lines[1..].iter().take_while(|line| line.trim().is_empty()).count();
+    match blank_lines {
+        0 => violations.push(StructureViolation::NoBlankLineAfterSubject),
+        1 => {}
+        _ => violations.push(StructureViolation::ExtraBlankLinesAfterSubject),
+    }
+    let body_start = 1 + blank_lines;
+    let footers = lines[body_start..].iter().rev().take_while(|line| is_footer(line)).count();
+    let footer_start = lines.len() - footers;
+    if footers > 0 && footer_start > body_start && !lines[footer_start - 1].trim().is_empty() {
+        violations.push(StructureViolation::NoBlankLineBeforeFooters);
+    }
+    violations
+}
+
+/// Capitalized words kept as they are at the start of a description
+const PRESERVED_WORDS: [&str; 8] =
+    ["GitHub", "GitLab", "JavaScript", "TypeScript", "OAuth", "Docker", "Rust", "Windows"];
+
+/// Lowercases the first letter of the description and drops a trailing period, e.g.
+/// `feat: Add Feature.` becomes `feat: add Feature`. Acronyms (`API`), identifiers with
+/// inner capitals or digits (`HashMap`, `S3`) and [`PRESERVED_WORDS`] keep their case.
+pub fn normalize_description(message: &str) -> String {
+    let Some(mut parsed) = parse_conventional(message) else {
+        return message.to_string();
+    };
+    if parsed.description.ends_with('.') && !parsed.description.ends_with("..") {
+        parsed.description.pop();
+    }
+
+    let first_word = parsed.description.split_whitespace().next().unwrap_or_default();
+    let word = first_word.trim_end_matches(|c: char| !c.is_alphanumeric());
+    let is_identifier = word.chars().skip(1).any(|c| c.is_uppercase() || c.is_ascii_digit())
+        || !word.chars().all(char::is_alphanumeric);
+    if !is_identifier && !PRESERVED_WORDS.contains(&word) {
+        let mut chars = parsed.description.chars();
+        if let Some(first) = chars.next() {
+            parsed.description = first.to_lowercase().chain(chars).collect();
+        }
+    }
+
+    match message.split_once('\n') {
+        Some((_, rest)) => format!("{}\n{}", parsed.subject(), rest),
+        None => parsed.subject(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    #[test_case("feat: add x", "feat", None, false; "type only")]
+    #[test_case("fix(core): resolve issue", "fix", Some("core"), false; "with scope")]
+    #[test_case("feat!: drop v1", "feat", None, true; "breaking")]
+    #[test_case("feat(api)!: drop v1\n\nbody", "feat", Some("api"), true; "breaking with scope and body")]
+    #[test_case("chore(deps-dev): bump x", "chore", Some("deps-dev"), false; "hyphenated scope")]
+    fn test_parse_conventional(message: &str, commit_type: &str, scope: Option<&str>, breaking: bool) {
+        let parsed = parse_conventional(message).unwrap();
+        assert_eq!(parsed.commit_type, commit_type);
+        assert_eq!(parsed.scope.as_deref(), scope);
+        assert_eq!(parsed.breaking, breaking);
+    }
+
+    #[test_case("feat: Add Feature.", "feat: add Feature"; "capital and period")]
+    #[test_case("fix(api): Handle timeouts\n\nBody stays.", "fix(api): handle timeouts\n\nBody stays."; "with body")]
+    #[test_case("docs: README tweaks.", "docs: README tweaks"; "acronym")]
+    #[test_case("feat: HashMap-based cache", "feat: HashMap-based cache"; "identifier")]
+    #[test_case("ci: GitHub actions", "ci: GitHub actions"; "allowlisted")]
+    #[test_case("feat: Support S3 uploads", "feat: support S3 uploads"; "later words untouched")]
+    #[test_case("feat: add more...", "feat: add more..."; "ellipsis")]
+    #[test_case("Add Feature.", "Add Feature."; "not conventional")]
+    fn test_normalize_description(message: &str, expected: &str) {
+        assert_eq!(normalize_description(message), expected);
+    }
+
+    #[test_case("feat(api/auth): x", Some("/"), vec!["api", "auth"]; "slash")]
+    #[test_case("feat(api::auth): x", Some("::"), vec!["api", "auth"]; "multi-char separator")]
+    #[test_case("feat(api/auth): x", None, vec!["api/auth"]; "no separator configured")]
+    #[test_case("feat: x", Some("/"), vec![]; "no scope")]
+    fn test_scope_parts(message: &str, separator: Option<&str>, expected: Vec<&str>) {
+        assert_eq!(parse_conventional(message).unwrap().scope_parts(separator), expected);
+    }
+
+    #[test_case("feat: add x", vec![]; "subject only")]
+    #[test_case("feat: add x\n\n- detail\n\nCloses #42\nReviewed-by: Jane", vec![]; "body and footers")]
+    #[test_case("feat: add x\n\nBREAKING CHANGE: drops v1", vec![]; "footer only body")]
+    #[test_case("feat: add x.", vec![StructureViolation::SubjectEndsWithPunctuation('.')]; "subject period")]
+    #[test_case("feat: add x\n- detail", vec![StructureViolation::NoBlankLineAfterSubject]; "no blank line")]
+    #[test_case("feat: add x\n\n\n- detail", vec![StructureViolation::ExtraBlankLinesAfterSubject]; "two blank lines")]
+    #[test_case("feat: add x\n\n- detail\nCloses #42", vec![StructureViolation::NoBlankLineBeforeFooters]; "footer without blank line")]
+    #[test_case("feat: add x!\n- detail", vec![
+        StructureViolation::SubjectEndsWithPunctuation('!'),
+        StructureViolation::NoBlankLineAfterSubject,
+    ]; "several")]
+    fn test_structure_violations(message: &str, expected: Vec<StructureViolation>) {
+        assert_eq!(structure_violations(message), expected);
+    }
+
+    #[test_case("random message"; "no separator")]
+    #[test_case("feat:add x"; "no space after colon")]
+    #[test_case("feat: "; "empty description")]
+    #[test_case(": add x"; "empty type")]
+    #[test_case("feat(): add x"; "empty scope")]
+    #[test_case("feat( ): add x"; "blank scope")]
+    #[test_case("feat(api: add x"; "unclosed scope")]
+    #[test_case("feat(a)(b): add x"; "two scopes")]
+    #[test_case("new feat: add x"; "whitespace in type")]
+    #[test_case(""; "empty")]
+    fn test_parse_conventional_rejects(message: &str) {
+        assert_eq!(parse_conventional(message), None);
+    }
+
+    #[test]
+    fn test_breaking_change_footer() {
+        let message = "feat(api)!: drop v1 endpoints\n\nRemove the deprecated routes.\n\n\
+            BREAKING CHANGE: clients must use /v2";
+        assert_eq!(breaking_change_footer(message), Some("clients must use /v2"));
+        assert_eq!(breaking_change_footer("feat!: drop v1\n\nBREAKING-CHANGE:"), Some(""));
+        assert_eq!(breaking_change_footer("feat!: drop v1"), None);
+    }
+
+    #[test]
+    fn test_mark_breaking() {
+        assert_eq!(mark_breaking("feat(api): drop v1\n\nbody"), "feat(api)!: drop v1\n\nbody");
+        assert_eq!(mark_breaking("feat!: drop v1"), "feat!: drop v1");
+    }
+
+    #[test]
+    fn test_subject_round_trips() {
+        let subject = "feat(auth)!: drop v1 tokens";
+        assert_eq!(parse_conventional(subject).unwrap().subject(), subject);
+    }
+}
@@ -0,0 +1,311 @@
+use std::sync::OnceLock;
+use tiktoken_rs::CoreBPE;
+
+/// Directory names too generic to describe an area of the code, e.g. as a scope
+pub const GENERIC_DIRS: [&str; 8] = ["src", "lib", "app", "pkg", "crates", "packages", "internal", "source"];
+
+/// Stands in for the hunks of generated or vendored files in diffs, which are left out so
+/// they don't drive the description
+pub const GENERATED_MARKER: &str = "@@ generated or vendored file, changes omitted @@";
+
+/// Comment syntax of a source file, derived from its extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CommentStyle {
+    /// `//` line comments and `/* */` block comments
+    CLike,
+    /// `#` line comments
+    Hash,
+    /// Unknown language, nothing is treated as a comment
+    None,
+}
+
+impl CommentStyle {
+    fn from_path(path: &str) -> Self {
+        match path.rsplit('.').next().unwrap_or("") {
+            "rs" | "c" | "h" | "cc" | "cpp" | "hpp" | "cs" | "go" | "java" | "js" | "jsx"
+            | "ts" | "tsx" | "kt" | "swift" | "scala" | "dart" | "php" | "css" | "scss" => {
+                CommentStyle::CLike
+            }
+            "py" | "sh" | "bash" | "zsh" | "rb" | "pl" | "r" | "toml" | "yaml" | "yml"
+            | "cfg" | "conf" | "ini" | "mk" | "cmake" => CommentStyle::Hash,
+            _ if path.ends_with("Makefile") || path.ends_with("Dockerfile") => CommentStyle::Hash,
+            _ => CommentStyle::None,
+        }
+    }
+}
+
+/// Tracks whether one side of a hunk is inside a `/* */` block comment
+#[derive(Default, Clone, Copy)]
+struct BlockState {
+    in_block: bool,
+}
+
+impl BlockState {
+    /// Returns whether `content` is a comment line, updating the block state
+    fn is_comment(&mut self, content: &str, style: CommentStyle) -> bool {
+        match style {
+            CommentStyle::CLike => {
+                let was_in_block = self.in_block;
+                let starts_block = content.starts_with("/*");
+                if starts_block || self.in_block {
+                    let rest = if starts_block { &content[2..] } else { content };
+                    self.in_block = !rest.contains("*/");
+                }
+                was_in_block || starts_block || content.starts_with("//")
+            }
+            CommentStyle::Hash => content.starts_with('#'),
+            CommentStyle::None => false,
+        }
+    }
+}
+
+/// Removes added and removed lines that only contain comments.
+///
+/// Context lines, file headers and hunk headers are kept as-is, so the result is still
+/// readable as a diff even though hunk line counts no longer add up.
+pub fn strip_comment_changes(diff: &str) -> String {
+    let mut output = String::with_capacity(diff.len());
+    let mut style = CommentStyle::None;
+    let mut added = BlockState::default();
+    let mut removed = BlockState::default();
+
+    for line in diff.lines() {
+        let keep = if line.starts_with("diff --git") {
+            style = CommentStyle::from_path(line.rsplit(' ').next().unwrap_or(""));
+            added = BlockState::default();
+            removed = BlockState::default();
+            true
+        } else if line.starts_with("+++") || line.starts_with("---") || line.starts_with("@@") {
+            true
+        } else if let Some(content) = line.strip_prefix('+') {
+            !added.is_comment(content.trim(), style)
+        } else if let Some(content) = line.strip_prefix('-') {
+            !removed.is_comment(content.trim(), style)
+        } else if let Some(content) = line.strip_prefix(' ') {
+            added.is_comment(content.trim(), style);
+            removed.is_comment(content.trim(), style);
+            true
+        } else {
+            true
+        };
+
+        if keep {
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+
+    output
+}
+
+/// Approximates the number of model tokens in `text` using the cl100k tokenizer
+pub fn count_tokens(text: &str) -> usize {
+    static BPE: OnceLock<Option<CoreBPE>> = OnceLock::new();
+    match BPE.get_or_init(|| tiktoken_rs::cl100k_base().ok()) {
+        Some(bpe) => bpe.encode_ordinary(text).len(),
+        // Roughly four characters per token for code
+        None => text.len() / 4,
+    }
+}
+
+/// Truncates `text` on a line boundary so it fits in `max_tokens`, marking the cut
+pub fn truncate_to_tokens(text: &str, max_tokens: usize) -> String {
+    if count_tokens(text) <= max_tokens {
+        return text.to_string();
+    }
+
+    const MARKER: &str = "[... truncated]\n";
+    let budget = max_tokens.saturating_sub(count_tokens(MARKER));
+    let mut used = 0;
+    let mut output = String::new();
+    for line in text.lines() {
+        let tokens = count_tokens(line) + 1;
+        if used + tokens > budget {
+            break;
+        }
+        used += tokens;
+        output.push_str(line);
+        output.push('\n');
+    }
+    output.push_str(MARKER);
+    output
+}
+
+/// One file's section of a unified diff
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDiff {
+    pub path: String,
+    pub diff: String,
+}
+
+/// Splits a unified diff into one section per file
+pub fn split_by_file(diff: &str) -> Vec<FileDiff> {
+    let mut files: Vec<FileDiff> = Vec::new();
+    for line in diff.split_inclusive('\n') {
+        if line.starts_with("diff --git") {
+            let path = line.trim_end().split(' ').next_back().unwrap_or("");
+            files.push(FileDiff {
+                path: path.strip_prefix("b/").unwrap_or(path).to_string(),
+                diff: String::new(),
+            });
+        }
+        if let Some(file) = files.last_mut() {
+            file.diff.push_str(line);
+        }
+    }
+    files
+}
+
+/// Splits files into those to summarize and those whose diffs are kept, so the kept diffs
+/// fit in `budget` tokens. The largest files are summarized first; both lists keep the
+/// original order.
+pub fn split_for_summary(files: Vec<FileDiff>, budget: usize) -> (Vec<FileDiff>, Vec<FileDiff>) {
+    let tokens: Vec<usize> = files.iter().map(|file| count_tokens(&file.diff)).collect();
+    let mut total: usize = tokens.iter().sum();
+    let mut by_size: Vec<usize> = (0..files.len()).collect();
+    by_size.sort_by_key(|&i| std::cmp::Reverse(tokens[i]));
+    let mut summarize = vec![false; files.len()];
+    for i in by_size {
+        if total <= budget {
+            break;
+        }
+        summarize[i] = true;
+        total -= tokens[i];
+    }
+    let (summarized, kept): (Vec<_>, Vec<_>) = files.into_iter().zip(summarize).partition(|(_, s)| *s);
+    (
+        summarized.into_iter().map(|(file, _)| file).collect(),
+        kept.into_iter().map(|(file, _)| file).collect(),
+    )
+}
+
+/// Paths of files whose added lines contain merge conflict markers (`<<<<<<<` or `>>>>>>>`)
+pub fn conflict_marker_files(diff: &str) -> Vec<String> {
+    let is_marker = |line: &str| {
+        ["<<<<<<<", ">>>>>>>"].iter().any(|marker| {
+            line.strip_prefix(marker)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+        })
+    };
+    split_by_file(diff)
+        .into_iter()
+        .filter(|file| {
+            file.diff
+                .lines()
+                .filter_map(|line| line.strip_prefix('+'))
+                .any(is_marker)
+        })
+        .map(|file| file.path)
+        .collect()
+}
+
+/// Groups files by the first directory that names an area of the code, skipping generic
+/// ones like `src`. Files outside any such directory are grouped under `root`.
+pub fn group_by_area(files: Vec<FileDiff>) -> Vec<(String, Vec<FileDiff>)> {
+    let mut groups: std::collections::BTreeMap<String, Vec<FileDiff>> = Default::default();
+    for file in files {
+        let mut dirs: Vec<&str> = file.path.split('/').collect();
+        dirs.pop();
+        let area = dirs
+            .into_iter()
+            .find(|dir| !GENERIC_DIRS.contains(dir))
+            .unwrap_or("root")
+            .to_string();
+        groups.entry(area).or_default().push(file);
+    }
+    groups.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_split_for_summary_takes_largest_files_first() {
+        let large: String = (0..100).map(|i| format!("+let value_{} = compute({});\n", i, i)).collect();
+        let medium: String = (0..30).map(|i| format!("+let value_{} = compute({});\n", i, i)).collect();
+        let diff = format!(
+            "diff --git a/small.rs b/small.rs\n+a\n\
+            diff --git a/large.rs b/large.rs\n{}\
+            diff --git a/medium.rs b/medium.rs\n{}\
+            diff --git a/tiny.rs b/tiny.rs\n+b\n",
+            large, medium
+        );
+        let paths = |files: &[FileDiff]| files.iter().map(|f| f.path.clone()).collect::<Vec<_>>();
+
+        let budget = count_tokens(&diff) - count_tokens(&large) + 10;
+        let (summarized, kept) = split_for_summary(split_by_file(&diff), budget);
+        assert_eq!(paths(&summarized), vec!["large.rs"]);
+        assert_eq!(paths(&kept), vec!["small.rs", "medium.rs", "tiny.rs"]);
+
+        let (summarized, kept) = split_for_summary(split_by_file(&diff), 50);
+        assert_eq!(paths(&summarized), vec!["large.rs", "medium.rs"]);
+        assert_eq!(paths(&kept), vec!["small.rs", "tiny.rs"]);
+
+        let (summarized, _) = split_for_summary(split_by_file(&diff), count_tokens(&diff));
+        assert!(summarized.is_empty());
+    }
+
+    #[test]
+    fn test_split_and_group_by_area() {
+        let diff = "diff --git a/src/auth/login.rs b/src/auth/login.rs\n+a\n\
+            diff --git a/README.md b/README.md\n+b\n\
+            diff --git a/src/auth/token.rs b/src/auth/token.rs\n+c\n\
+            diff --git a/src/main.rs b/src/main.rs\n+d\n";
+        let files = split_by_file(diff);
+        assert_eq!(files.len(), 4);
+        assert_eq!(files[0].diff, "diff --git a/src/auth/login.rs b/src/auth/login.rs\n+a\n");
+
+        let groups: Vec<(String, Vec<String>)> = group_by_area(files)
+            .into_iter()
+            .map(|(area, files)| (area, files.into_iter().map(|f| f.path).collect()))
+            .collect();
+        assert_eq!(
+            groups,
+            vec![
+                ("auth".to_string(), vec!["src/auth/login.rs".to_string(), "src/auth/token.rs".to_string()]),
+                ("root".to_string(), vec!["README.md".to_string(), "src/main.rs".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strip_comment_changes() {
+        let diff = "diff --git a/src/lib.rs b/src/lib.rs\n\
+            @@ -1,3 +1,6 @@\n\
+            -// old comment\n\
+            +/// New doc comment\n\
+            +/* block\n\
+            + * still comment\n\
+            + */\n\
+            +let x = *ptr;\n\
+            diff --git a/run.sh b/run.sh\n\
+            +# comment\n\
+            +echo done\n";
+        let expected = "diff --git a/src/lib.rs b/src/lib.rs\n\
+            @@ -1,3 +1,6 @@\n\
+            +let x = *ptr;\n\
+            diff --git a/run.sh b/run.sh\n\
+            +echo done\n";
+        assert_eq!(strip_comment_changes(diff), expected);
+    }
+
+    #[test]
+    fn test_truncate_to_tokens_keeps_whole_lines() {
+        let text: String = (0..50).map(|i| format!("+line number {}\n", i)).collect();
+        assert_eq!(truncate_to_tokens(&text, 10_000), text);
+
+        let truncated = truncate_to_tokens(&text, 30);
+        assert!(truncated.starts_with("+line number 0\n"));
+        assert!(truncated.ends_with("[... truncated]\n"));
+        assert!(count_tokens(&truncated) <= 30);
+    }
+
+    #[test]
+    fn test_conflict_marker_files() {
+        let diff = "diff --git a/a.rs b/a.rs\n+++ b/a.rs\n+<<<<<<< HEAD\n+let x = 1;\n+=======\n+let x = 2;\n+>>>>>>> feature\n\
+                    diff --git a/README.md b/README.md\n+++ b/README.md\n+Title\n+=======\n- <<<<<<< removed\n";
+        assert_eq!(conflict_marker_files(diff), vec!["a.rs".to_string()]);
+    }
+}
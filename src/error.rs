@@ -14,6 +14,12 @@ pub enum Error {
     }))]
     Request(#[from] reqwest::Error),
 
+    #[error("API error ({status}): {message}")]
+    ApiResponse { status: StatusCode, message: String },
+
+    #[error("Invalid API response: {0}")]
+    InvalidResponse(#[from] serde_json::Error),
+
     #[error("Environment error: {0}")]
     Env(#[from] std::env::VarError),
 
@@ -21,16 +27,90 @@ pub enum Error {
     Io(#[from] std::io::Error),
 
     #[error("Configuration error: {0}")]
-    Config(#[from] toml::de::Error),
+    Config(String),
+
+    #[error("Invalid value {value:?} for environment variable {name}")]
+    InvalidEnvVar { name: String, value: String },
+
+    #[error("Failed to write settings: {0}")]
+    Settings(#[from] toml::ser::Error),
+
+    #[error("File watch error: {0}")]
+    Watch(#[from] notify::Error),
+
+    #[error("Invalid ignore pattern: {0}")]
+    Pattern(#[from] glob::PatternError),
+
+    #[error("Invalid commit date {0:?}, expected RFC 3339 such as 2024-01-31T12:00:00+01:00")]
+    InvalidDate(String),
+
+    #[error("Invalid custom_regex: {0}")]
+    Regex(#[from] regex::Error),
+
+    #[error("Invalid trailer on line {line}: {text:?}, expected `Token: value`")]
+    InvalidTrailer { line: usize, text: String },
+
+    #[error("Invalid HTTP header: {0}")]
+    InvalidHeader(String),
+
+    #[error("Branch '{0}' has no upstream. Set one with 'git push -u <remote> {0}'")]
+    NoUpstream(String),
+
+    #[error("Push failed: {0}")]
+    Push(String),
+
+    #[error("Merge conflict markers found in: {}. Resolve them or pass --force", .0.join(", "))]
+    ConflictMarkers(Vec<String>),
+
+    #[error("Replay failed: {0}")]
+    Replay(String),
+
+    #[error("Recording failed: {0}")]
+    Recording(String),
+
+    #[error("Cannot serialize the API request: {0}")]
+    RequestSerialization(String),
+
+    #[error("All changes are excluded from the diff by: {}", .0.join(", "))]
+    AllChangesFiltered(Vec<String>),
+
+    #[error("No tag found in the history of HEAD")]
+    NoTag,
+
+    #[error("No stash entry stash@{{{index}}}, there are {count} stash entries")]
+    NoStash { index: usize, count: usize },
 
     #[error("No changes to commit. Make sure you have staged your changes with 'git add'")]
     NoChanges,
 
-    #[error("API key not provided. Set TOGETHER_API_KEY environment variable or use --api-key")]
+    #[error("API key not provided. Set TOGETHER_API_KEY (or HF_TOKEN for Hugging Face) environment variable or use --api-key")]
     NoApiKey,
 
     #[error("Failed to generate commit message: {0}")]
     CommitMessageGeneration(String),
+
+    #[error("Generated message does not follow conventional commit format after all retries. Last attempt: {last_attempt}")]
+    ValidationExhausted { last_attempt: String },
+
+    #[error("{0} health check(s) failed")]
+    HealthCheck(usize),
+
+    #[error("Commit message generation was cancelled")]
+    Cancelled,
+
+    #[error("Invalid commit message:\n  - {}", .0.join("\n  - "))]
+    InvalidMessage(Vec<String>),
+}
+
+impl Error {
+    /// HTTP status of a failed API request, whether or not the provider explained it
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Error::Request(e) => e.status(),
+            Error::ApiResponse { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>; 
\ No newline at end of file
@@ -1,77 +1,458 @@
-use git2::{DiffOptions, Repository, StatusOptions};
-use crate::{Error, Result, GitConfig};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use git2::{
+    AttrCheckFlags, AttrValue, Cred, CredentialType, DiffOptions, ErrorClass, ErrorCode, PushOptions, RemoteCallbacks, Repository,
+    RepositoryOpenFlags,
+    Signature, Status, StatusOptions, Time,
+};
+use glob::Pattern;
+use crate::{DiffAlgorithm, Error, Result, GitConfig};
+use crate::diff::{GENERATED_MARKER, GENERIC_DIRS};
+
+/// Per-repository ignore file listing paths to leave out of the generation diff
+pub const IGNORE_FILE: &str = ".commit-sage-ignore";
 
 pub struct GitRepo {
     repo: Repository,
     config: GitConfig,
+    commit_time: Option<Time>,
+}
+
+/// Parses an RFC 3339 date (e.g. `2024-01-31T12:00:00+01:00`) into a commit time
+pub fn parse_commit_date(date: &str) -> Result<Time> {
+    let parsed = chrono::DateTime::parse_from_rfc3339(date.trim())
+        .map_err(|_| Error::InvalidDate(date.to_string()))?;
+    Ok(Time::new(parsed.timestamp(), parsed.offset().local_minus_utc() / 60))
 }
 
 impl GitRepo {
+    /// Opens the repository at or above `repo_path`, including linked worktrees, and
+    /// honoring `GIT_DIR`, `GIT_WORK_TREE` and the other variables git itself reads
     pub fn new(config: GitConfig) -> Result<Self> {
+        let repo = Repository::open_ext(
+            &config.repo_path,
+            RepositoryOpenFlags::FROM_ENV,
+            std::iter::empty::<&std::ffi::OsStr>(),
+        )?;
         Ok(Self {
-            repo: Repository::open(&config.repo_path)?,
+            repo,
             config,
+            commit_time: None,
         })
     }
 
+    /// Uses the given time instead of the current one for the author and committer of
+    /// new commits, e.g. to backfill history or make commits reproducible
+    pub fn with_commit_time(mut self, time: Time) -> Self {
+        self.commit_time = Some(time);
+        self
+    }
+
     pub fn get_diff(&self) -> Result<String> {
-        let mut diff_options = DiffOptions::new();
+        let mut diff_options = self.diff_options();
         diff_options.include_untracked(self.config.include_untracked);
         
-        let diff = if self.is_initial_commit()? {
+        let diff = if self.index_only() {
+            // Only what is already in the index, so partially staged files (`git add -p`)
+            // contribute just their staged hunks
+            let head_tree = if self.is_initial_commit()? {
+                None
+            } else {
+                Some(self.repo.head()?.peel_to_tree()?)
+            };
+            let index = self.repo.index()?;
+            self.repo.diff_tree_to_index(head_tree.as_ref(), Some(&index), Some(&mut diff_options))?
+        } else if self.is_initial_commit()? {
             // For initial commits, diff against an empty tree
             let empty_tree = self.repo.find_tree(self.repo.treebuilder(None)?.write()?)?;
-            let mut index = self.repo.index()?;
-            index.add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)?;
-            index.write()?;
-            let tree_id = index.write_tree()?;
-            let tree = self.repo.find_tree(tree_id)?;
+            let tree = self.staged_tree()?;
             self.repo.diff_tree_to_tree(Some(&empty_tree), Some(&tree), Some(&mut diff_options))?
         } else {
-            // For normal commits, diff against HEAD using the index
+            // For normal commits, diff against HEAD what the commit will stage
             let head_tree = self.repo.head()?.peel_to_tree()?;
-            let mut index = self.repo.index()?;
-            index.add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)?;
-            index.write()?;
-            let tree_id = index.write_tree()?;
-            let tree = self.repo.find_tree(tree_id)?;
+            let tree = self.staged_tree()?;
             self.repo.diff_tree_to_tree(Some(&head_tree), Some(&tree), Some(&mut diff_options))?
         };
         
+        self.non_empty_diff(&diff)
+    }
+
+    /// The tree `commit` will record once it stages all changes, built in memory so that
+    /// computing a diff (again, when a message is regenerated) never touches the index.
+    /// Staging only happens in `commit`.
+    fn staged_tree(&self) -> Result<git2::Tree<'_>> {
+        let mut index = self.repo.index()?;
+        index.add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)?;
+        let tree_id = index.write_tree()?;
+        // Drop the in-memory additions again, the index is shared by the repository
+        index.read(true)?;
+        Ok(self.repo.find_tree(tree_id)?)
+    }
+
+    /// Diff of the working tree against HEAD, staged or not, without touching the index
+    pub fn get_working_tree_diff(&self) -> Result<String> {
+        let mut diff_options = self.diff_options();
+        diff_options
+            .include_untracked(self.config.include_untracked)
+            .recurse_untracked_dirs(true)
+            .show_untracked_content(true);
+        let head_tree = if self.is_initial_commit()? {
+            None
+        } else {
+            Some(self.repo.head()?.peel_to_tree()?)
+        };
+        let diff = self
+            .repo
+            .diff_tree_to_workdir_with_index(head_tree.as_ref(), Some(&mut diff_options))?;
+
+        self.non_empty_diff(&diff)
+    }
+
+    /// Returns the diff introduced by the HEAD commit, or `None` before the first commit
+    pub fn last_commit_diff(&self) -> Result<Option<String>> {
+        let Ok(head) = self.repo.head() else {
+            return Ok(None);
+        };
+        let diff = self.diff_against_parent(&head.peel_to_commit()?)?;
+        Ok(Some(self.diff_to_string(&diff)?))
+    }
+
+    /// Returns the diff introduced by a commit given as any revision (`a1b2c3d`, `HEAD~2`),
+    /// e.g. to draft a better message for it during a rebase
+    pub fn commit_diff(&self, rev: &str) -> Result<String> {
+        let commit = self.repo.revparse_single(rev)?.peel_to_commit()?;
+        let diff = self.diff_against_parent(&commit)?;
+        self.non_empty_diff(&diff)
+    }
+
+    /// The changes of a commit against its first parent, or against the empty tree for
+    /// a root commit
+    fn diff_against_parent(&self, commit: &git2::Commit) -> Result<git2::Diff<'_>> {
+        let parent_tree = match commit.parent(0) {
+            Ok(parent) => Some(parent.tree()?),
+            Err(_) => None,
+        };
+        Ok(self.repo.diff_tree_to_tree(
+            parent_tree.as_ref(),
+            Some(&commit.tree()?),
+            Some(&mut self.diff_options()),
+        )?)
+    }
+
+    /// The most recent tag reachable from HEAD and the diff from it to HEAD, or `None` if
+    /// no tag is reachable, e.g. to summarize the changes going into a release
+    pub fn diff_since_last_tag(&self) -> Result<Option<(String, String)>> {
+        let mut tagged: HashMap<git2::Oid, String> = HashMap::new();
+        for name in self.repo.tag_names(None)?.iter().flatten() {
+            let target = self.repo.revparse_single(&format!("refs/tags/{}", name))?;
+            if let Ok(commit) = target.peel_to_commit() {
+                tagged.entry(commit.id()).or_insert_with(|| name.to_string());
+            }
+        }
+        if tagged.is_empty() {
+            return Ok(None);
+        }
+
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.push_head()?;
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+        for oid in revwalk {
+            let oid = oid?;
+            let Some(tag) = tagged.get(&oid) else { continue };
+            let tag_tree = self.repo.find_commit(oid)?.tree()?;
+            let head_tree = self.repo.head()?.peel_to_tree()?;
+            let diff = self.repo.diff_tree_to_tree(
+                Some(&tag_tree),
+                Some(&head_tree),
+                Some(&mut self.diff_options()),
+            )?;
+            return Ok(Some((tag.clone(), self.non_empty_diff(&diff)?)));
+        }
+        Ok(None)
+    }
+
+    /// Sorted top-level directories, with the modules inside generic source directories
+    /// like `src` listed instead (`src/auth`, `crates/core`). Read from the index, so
+    /// untracked and ignored paths are left out, as are hidden directories and entry points
+    /// like `main.rs`.
+    pub fn module_names(&self) -> Result<Vec<String>> {
+        const ENTRY_POINTS: [&str; 4] = ["lib", "main", "mod", "index"];
+        let mut names = BTreeSet::new();
+        for entry in self.repo.index()?.iter() {
+            let path = String::from_utf8_lossy(&entry.path).into_owned();
+            let parts: Vec<&str> = path.split('/').collect();
+            match parts.as_slice() {
+                [dir, child, rest @ ..] if GENERIC_DIRS.contains(dir) => {
+                    let name = if rest.is_empty() { child.split('.').next().unwrap_or(child) } else { child };
+                    if !name.is_empty() && !ENTRY_POINTS.contains(&name) {
+                        names.insert(format!("{}/{}", dir, name));
+                    }
+                }
+                [dir, _, ..] if !dir.starts_with('.') => {
+                    names.insert(dir.to_string());
+                }
+                _ => {}
+            }
+        }
+        Ok(names.into_iter().collect())
+    }
+
+    /// The diff of the stash entry `stash@{index}` against the commit it was made on.
+    /// Untracked files stashed with `--include-untracked` are not part of it.
+    pub fn stash_diff(&self, index: usize) -> Result<String> {
+        let reflog = match self.repo.reflog("refs/stash") {
+            Ok(reflog) => reflog,
+            Err(e) if e.code() == ErrorCode::NotFound => return Err(Error::NoStash { index, count: 0 }),
+            Err(e) => return Err(e.into()),
+        };
+        let entry = reflog.get(index).ok_or(Error::NoStash { index, count: reflog.len() })?;
+        let stash = self.repo.find_commit(entry.id_new())?;
+        let base = stash.parent(0)?;
+        let diff = self.repo.diff_tree_to_tree(
+            Some(&base.tree()?),
+            Some(&stash.tree()?),
+            Some(&mut self.diff_options()),
+        )?;
+        self.non_empty_diff(&diff)
+    }
+
+    /// Name of the checked out branch, or `None` on a detached HEAD. Also works
+    /// before the first commit.
+    pub fn current_branch(&self) -> Result<Option<String>> {
+        let head = self.repo.find_reference("HEAD")?;
+        Ok(head
+            .symbolic_target()
+            .and_then(|target| target.strip_prefix("refs/heads/"))
+            .map(String::from))
+    }
+
+    /// Diff options with the configured diff algorithm
+    fn diff_options(&self) -> DiffOptions {
+        let mut options = DiffOptions::new();
+        match self.config.diff_algorithm {
+            DiffAlgorithm::Myers => {}
+            DiffAlgorithm::Minimal => {
+                options.minimal(true);
+            }
+            DiffAlgorithm::Patience | DiffAlgorithm::Histogram => {
+                options.patience(true);
+            }
+        }
+        options
+    }
+
+    /// Renders a diff, failing with `NoChanges` if it is empty, or with `AllChangesFiltered`
+    /// naming the matching patterns if every changed file is excluded
+    fn non_empty_diff(&self, diff: &git2::Diff) -> Result<String> {
+        let diff_string = self.diff_to_string(diff)?;
+        if !diff_string.is_empty() {
+            return Ok(diff_string);
+        }
+        let excluded = self.exclude_patterns()?;
+        let mut filters: Vec<String> = Vec::new();
+        for delta in diff.deltas() {
+            let path = delta.new_file().path().or_else(|| delta.old_file().path());
+            let Some(path) = path else { continue };
+            for (pattern, source) in &excluded {
+                let filter = format!("{} ({})", pattern.as_str(), source);
+                if pattern.matches_path(path) && !filters.contains(&filter) {
+                    filters.push(filter);
+                }
+            }
+        }
+        if filters.is_empty() {
+            return Err(Error::NoChanges);
+        }
+        Err(Error::AllChangesFiltered(filters))
+    }
+
+    /// Renders a diff as a patch, leaving out excluded paths
+    fn diff_to_string(&self, diff: &git2::Diff) -> Result<String> {
+        let excluded = self.exclude_patterns()?;
+        let generated = self.config.generated_paths
+            .iter()
+            .map(|p| Pattern::new(p))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
         let mut diff_string = String::new();
-        diff.print(git2::DiffFormat::Patch, |_, _, line| {
+        // Whether the current file is generated, and if its hunks were replaced already
+        let mut current: Option<(PathBuf, bool, bool)> = None;
+        diff.print(git2::DiffFormat::Patch, |delta, _, line| {
+            let path = delta.new_file().path().or_else(|| delta.old_file().path());
+            if let Some(path) = path {
+                if excluded.iter().any(|(p, _)| p.matches_path(path)) {
+                    return true;
+                }
+                if current.as_ref().is_none_or(|(current, _, _)| current != path) {
+                    let is_generated = generated.iter().any(|p| p.matches_path(path))
+                        || self.is_linguist_generated(path);
+                    current = Some((path.to_path_buf(), is_generated, false));
+                }
+            }
+            if let Some((_, true, replaced)) = current.as_mut() {
+                if line.origin() != 'F' {
+                    if !*replaced {
+                        diff_string.push_str(GENERATED_MARKER);
+                        diff_string.push('\n');
+                        *replaced = true;
+                    }
+                    return true;
+                }
+            }
+            if matches!(line.origin(), '+' | '-' | ' ') {
+                diff_string.push(line.origin());
+            }
             diff_string.push_str(&String::from_utf8_lossy(line.content()));
             true
         })?;
-        
-        if diff_string.is_empty() {
-            return Err(Error::NoChanges);
-        }
-        
+
         Ok(diff_string)
     }
 
+    /// Whether `.gitattributes` marks a path as `linguist-generated`
+    fn is_linguist_generated(&self, path: &Path) -> bool {
+        let value = self.repo.get_attr(path, "linguist-generated", AttrCheckFlags::default());
+        match value.map(AttrValue::from_string) {
+            Ok(AttrValue::True) => true,
+            Ok(AttrValue::String(value)) => value == "true",
+            _ => false,
+        }
+    }
+
+    /// Collects exclusion globs from `GitConfig::exclude_paths` and the repo's ignore file,
+    /// each with the name of its source. A path matching a pattern from either source is
+    /// excluded.
+    fn exclude_patterns(&self) -> Result<Vec<(Pattern, &'static str)>> {
+        let mut patterns: Vec<(String, &'static str)> = self
+            .config
+            .exclude_paths
+            .iter()
+            .map(|p| (p.clone(), "exclude_paths"))
+            .collect();
+        if let Some(workdir) = self.repo.workdir() {
+            let ignore_path = workdir.join(IGNORE_FILE);
+            if ignore_path.is_file() {
+                let contents = std::fs::read_to_string(ignore_path)?;
+                patterns.extend(
+                    contents
+                        .lines()
+                        .map(str::trim)
+                        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                        .map(|l| (l.to_string(), IGNORE_FILE)),
+                );
+            }
+        }
+
+        patterns
+            .iter()
+            .map(|(p, source)| Ok((Pattern::new(p)?, *source)))
+            .collect()
+    }
+
+    /// Root of the work tree, or `None` for a bare repository
+    pub fn workdir(&self) -> Option<&Path> {
+        self.repo.workdir()
+    }
+
+    /// Whether an absolute path is outside the work tree, inside `.git`, or gitignored
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let Some(workdir) = self.repo.workdir() else {
+            return true;
+        };
+        let Ok(relative) = path.strip_prefix(workdir) else {
+            return true;
+        };
+        relative.starts_with(".git") || self.repo.status_should_ignore(relative).unwrap_or(false)
+    }
+
+    /// Whether only the existing index is described and committed, without staging
+    fn index_only(&self) -> bool {
+        self.config.staged_only || !self.config.auto_stage
+    }
+
     fn is_initial_commit(&self) -> Result<bool> {
         Ok(self.repo.head().is_err())
     }
 
+    /// The changed files with their status (`added`, `modified`, `deleted` or `renamed`),
+    /// from git status rather than the diff so none are lost when the diff is truncated.
+    /// Only staged changes count when the existing index is committed as-is.
+    pub fn changed_files(&self) -> Result<Vec<(&'static str, String)>> {
+        let mut status_options = StatusOptions::new();
+        status_options
+            .include_untracked(self.config.include_untracked && !self.index_only())
+            .recurse_untracked_dirs(true)
+            .renames_head_to_index(true);
+        let mut considered = Status::INDEX_NEW
+            | Status::INDEX_MODIFIED
+            | Status::INDEX_DELETED
+            | Status::INDEX_RENAMED
+            | Status::INDEX_TYPECHANGE;
+        if !self.index_only() {
+            considered |= Status::WT_NEW
+                | Status::WT_MODIFIED
+                | Status::WT_DELETED
+                | Status::WT_RENAMED
+                | Status::WT_TYPECHANGE;
+        }
+
+        let mut files = Vec::new();
+        for entry in self.repo.statuses(Some(&mut status_options))?.iter() {
+            let status = entry.status() & considered;
+            let Some(path) = entry.path().filter(|_| !status.is_empty()) else { continue };
+            let label = if status.intersects(Status::INDEX_NEW | Status::WT_NEW) {
+                "added"
+            } else if status.intersects(Status::INDEX_DELETED | Status::WT_DELETED) {
+                "deleted"
+            } else if status.intersects(Status::INDEX_RENAMED | Status::WT_RENAMED) {
+                "renamed"
+            } else {
+                "modified"
+            };
+            files.push((label, path.to_string()));
+        }
+        Ok(files)
+    }
+
     pub fn has_changes(&self) -> Result<bool> {
         let mut status_options = StatusOptions::new();
         status_options.include_untracked(self.config.include_untracked);
         
         let statuses = self.repo.statuses(Some(&mut status_options))?;
+        if self.index_only() {
+            let staged = Status::INDEX_NEW
+                | Status::INDEX_MODIFIED
+                | Status::INDEX_DELETED
+                | Status::INDEX_RENAMED
+                | Status::INDEX_TYPECHANGE;
+            return Ok(statuses.iter().any(|entry| entry.status().intersects(staged)));
+        }
         Ok(!statuses.is_empty())
     }
 
+    /// Commits the staged changes. With nothing staged this creates an empty commit
+    /// whose tree equals its parent's, so callers must check `has_changes` themselves.
     pub fn commit(&self, message: &str) -> Result<()> {
-        // First stage all changes
-        self.stage_all()?;
-
+        // First stage all changes, unless only the current index should be committed
         let mut index = self.repo.index()?;
+        if self.index_only() {
+            // Drop any in-memory additions so only what is staged on disk is committed
+            index.read(true)?;
+        } else {
+            self.stage_all()?;
+        }
+
         let tree_id = index.write_tree()?;
         let tree = self.repo.find_tree(tree_id)?;
 
         let signature = self.repo.signature()?;
+        let signature = match &self.commit_time {
+            Some(time) => Signature::new(
+                signature.name().unwrap_or_default(),
+                signature.email().unwrap_or_default(),
+                time,
+            )?,
+            None => signature,
+        };
         let parent = match self.repo.head() {
             Ok(head) => Some(head.peel_to_commit()?),
             Err(_) => None,
@@ -90,10 +471,556 @@ impl GitRepo {
         Ok(())
     }
 
+    /// Pushes the current branch to its upstream. Credentials come from the SSH agent
+    /// for SSH remotes and from the configured git credential helper otherwise.
+    pub fn push(&self) -> Result<()> {
+        let branch = self
+            .current_branch()?
+            .ok_or_else(|| Error::Push("HEAD is detached, check out a branch to push".to_string()))?;
+        let refname = format!("refs/heads/{}", branch);
+        let git_config = self.repo.config()?;
+        let (remote_name, merge) = match (
+            git_config.get_string(&format!("branch.{}.remote", branch)),
+            git_config.get_string(&format!("branch.{}.merge", branch)),
+        ) {
+            (Ok(remote), Ok(merge)) => (remote, merge),
+            _ => return Err(Error::NoUpstream(branch)),
+        };
+        let mut remote = self.repo.find_remote(&remote_name)?;
+
+        let mut tried = CredentialType::empty();
+        let mut rejection = None;
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.credentials(|url, username, allowed| {
+            let username = username.unwrap_or("git");
+            // libgit2 calls back again after a failed attempt, so try each kind once
+            if allowed.contains(CredentialType::SSH_KEY) && !tried.contains(CredentialType::SSH_KEY) {
+                tried |= CredentialType::SSH_KEY;
+                return Cred::ssh_key_from_agent(username);
+            }
+            if allowed.contains(CredentialType::USER_PASS_PLAINTEXT)
+                && !tried.contains(CredentialType::USER_PASS_PLAINTEXT)
+            {
+                tried |= CredentialType::USER_PASS_PLAINTEXT;
+                return Cred::credential_helper(&git_config, url, Some(username));
+            }
+            if allowed.contains(CredentialType::USERNAME) && !tried.contains(CredentialType::USERNAME) {
+                tried |= CredentialType::USERNAME;
+                return Cred::username(username);
+            }
+            Err(git2::Error::from_str("no more credentials to try"))
+        });
+        callbacks.push_update_reference(|_, status| {
+            if let Some(status) = status {
+                rejection = Some(status.to_string());
+            }
+            Ok(())
+        });
+
+        let mut options = PushOptions::new();
+        options.remote_callbacks(callbacks);
+        remote
+            .push(&[format!("{}:{}", refname, merge)], Some(&mut options))
+            .map_err(|e| match (e.code(), e.class()) {
+                (ErrorCode::Auth, _) | (_, ErrorClass::Ssh) | (_, ErrorClass::Http) => Error::Push(format!(
+                    "authentication with '{}' failed ({}). Load your key into ssh-agent or configure \
+                     a git credential helper holding a token",
+                    remote_name,
+                    e.message()
+                )),
+                _ => Error::Git(e),
+            })?;
+        drop(options);
+
+        match rejection {
+            Some(status) => Err(Error::Push(format!("'{}' rejected {}: {}", remote_name, merge, status))),
+            None => Ok(()),
+        }
+    }
+
     pub fn stage_all(&self) -> Result<()> {
         let mut index = self.repo.index()?;
         index.add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)?;
         index.write()?;
         Ok(())
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CommitContext;
+    use std::fs;
+    use test_case::test_case;
+
+    fn commit_all(repo: &Repository, message: &str) {
+        let mut index = repo.index().unwrap();
+        index.add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("Test", "test@example.com").unwrap();
+        let parent = repo.head().ok().map(|h| h.peel_to_commit().unwrap());
+        let parents: Vec<_> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &signature, &signature, message, &tree, &parents)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_diff_lines_keep_their_origin_marker() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("file.txt"), "old line\nkept line\n").unwrap();
+        commit_all(&repo, "initial");
+        fs::write(dir.path().join("file.txt"), "new line\nkept line\n").unwrap();
+
+        let config = GitConfig {
+            repo_path: dir.path().to_path_buf(),
+            ..GitConfig::default()
+        };
+        let diff = GitRepo::new(config).unwrap().get_diff().unwrap();
+
+        assert!(diff.contains("\n-old line\n"));
+        assert!(diff.contains("\n+new line\n"));
+        assert!(diff.contains("\n kept line\n"));
+    }
+
+    #[test]
+    fn test_staged_only_diff_excludes_unstaged_hunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let lines: Vec<String> = (1..=20).map(|i| format!("line {}", i)).collect();
+        fs::write(dir.path().join("file.txt"), lines.join("\n") + "\n").unwrap();
+        commit_all(&repo, "initial");
+
+        // Stage only the first hunk, as `git add -p` would
+        let mut staged = lines.clone();
+        staged[0] = "staged change".to_string();
+        let mut index = repo.index().unwrap();
+        let mut entry = index.get_path(Path::new("file.txt"), 0).unwrap();
+        let content = staged.join("\n") + "\n";
+        entry.file_size = content.len() as u32;
+        index.add_frombuffer(&entry, content.as_bytes()).unwrap();
+        index.write().unwrap();
+
+        // The working tree also has a second, unstaged hunk
+        let mut worktree = staged.clone();
+        worktree[19] = "unstaged change".to_string();
+        fs::write(dir.path().join("file.txt"), worktree.join("\n") + "\n").unwrap();
+
+        let config = GitConfig {
+            repo_path: dir.path().to_path_buf(),
+            staged_only: true,
+            ..GitConfig::default()
+        };
+        let diff = GitRepo::new(config).unwrap().get_diff().unwrap();
+        let context = CommitContext::from_diff(&diff);
+
+        assert!(diff.contains("+staged change"));
+        assert!(!diff.contains("unstaged change"));
+        assert_eq!(context.total_additions, 1);
+        assert_eq!(context.total_deletions, 1);
+    }
+
+    #[test]
+    fn test_commit_without_auto_stage_excludes_unstaged_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let mut git_config = repo.config().unwrap();
+        git_config.set_str("user.name", "Test").unwrap();
+        git_config.set_str("user.email", "test@example.com").unwrap();
+        fs::write(dir.path().join("staged.txt"), "one\n").unwrap();
+        fs::write(dir.path().join("unstaged.txt"), "one\n").unwrap();
+        commit_all(&repo, "initial");
+
+        fs::write(dir.path().join("staged.txt"), "two\n").unwrap();
+        fs::write(dir.path().join("unstaged.txt"), "two\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("staged.txt")).unwrap();
+        index.write().unwrap();
+
+        let config = GitConfig {
+            repo_path: dir.path().to_path_buf(),
+            auto_stage: false,
+            ..GitConfig::default()
+        };
+        let git_repo = GitRepo::new(config).unwrap();
+        git_repo.get_diff().unwrap();
+        git_repo.commit("chore: update staged file").unwrap();
+
+        let tree = repo.head().unwrap().peel_to_tree().unwrap();
+        let content = |name: &str| {
+            let blob = repo.find_blob(tree.get_name(name).unwrap().id()).unwrap();
+            String::from_utf8(blob.content().to_vec()).unwrap()
+        };
+        assert_eq!(content("staged.txt"), "two\n");
+        assert_eq!(content("unstaged.txt"), "one\n");
+    }
+
+    #[test]
+    fn test_commit_with_custom_date() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let mut git_config = repo.config().unwrap();
+        git_config.set_str("user.name", "Test").unwrap();
+        git_config.set_str("user.email", "test@example.com").unwrap();
+        fs::write(dir.path().join("file.txt"), "one\n").unwrap();
+
+        let config = GitConfig {
+            repo_path: dir.path().to_path_buf(),
+            ..GitConfig::default()
+        };
+        let time = parse_commit_date("2024-01-31T12:00:00+01:00").unwrap();
+        GitRepo::new(config).unwrap().with_commit_time(time).commit("feat: add file").unwrap();
+
+        let commit = repo.head().unwrap().peel_to_commit().unwrap();
+        for signature in [commit.author(), commit.committer()] {
+            assert_eq!(signature.when().seconds(), 1_706_698_800);
+            assert_eq!(signature.when().offset_minutes(), 60);
+        }
+    }
+
+    #[test]
+    fn test_parse_commit_date_rejects_malformed_input() {
+        assert!(matches!(parse_commit_date("31/01/2024"), Err(Error::InvalidDate(_))));
+    }
+
+    #[test]
+    fn test_commit_without_changes_creates_empty_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let mut git_config = repo.config().unwrap();
+        git_config.set_str("user.name", "Test").unwrap();
+        git_config.set_str("user.email", "test@example.com").unwrap();
+        fs::write(dir.path().join("file.txt"), "one\n").unwrap();
+        commit_all(&repo, "initial");
+
+        let config = GitConfig {
+            repo_path: dir.path().to_path_buf(),
+            ..GitConfig::default()
+        };
+        let git_repo = GitRepo::new(config).unwrap();
+        assert!(!git_repo.has_changes().unwrap());
+        git_repo.commit("ci: trigger pipeline").unwrap();
+
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.message(), Some("ci: trigger pipeline"));
+        assert_eq!(head.tree_id(), head.parent(0).unwrap().tree_id());
+    }
+
+    #[test]
+    fn test_working_tree_diff_leaves_index_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("file.txt"), "one\n").unwrap();
+        commit_all(&repo, "initial");
+        fs::write(dir.path().join("file.txt"), "two\n").unwrap();
+        fs::write(dir.path().join("new.txt"), "new\n").unwrap();
+
+        let config = GitConfig {
+            repo_path: dir.path().to_path_buf(),
+            ..GitConfig::default()
+        };
+        let diff = GitRepo::new(config).unwrap().get_working_tree_diff().unwrap();
+
+        assert!(diff.contains("+two"));
+        assert!(diff.contains("+new"));
+        let statuses = repo.statuses(None).unwrap();
+        assert!(statuses.iter().all(|entry| !entry.status().is_index_modified() && !entry.status().is_index_new()));
+    }
+
+    #[test]
+    fn test_push_updates_upstream_branch() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        let bare = Repository::init_bare(remote_dir.path()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("file.txt"), "one\n").unwrap();
+        commit_all(&repo, "initial");
+
+        let branch = repo.head().unwrap().shorthand().unwrap().to_string();
+        repo.remote("origin", remote_dir.path().to_str().unwrap()).unwrap();
+        let mut git_config = repo.config().unwrap();
+        git_config.set_str(&format!("branch.{}.remote", branch), "origin").unwrap();
+        git_config.set_str(&format!("branch.{}.merge", branch), &format!("refs/heads/{}", branch)).unwrap();
+
+        let config = GitConfig {
+            repo_path: dir.path().to_path_buf(),
+            ..GitConfig::default()
+        };
+        GitRepo::new(config).unwrap().push().unwrap();
+
+        let pushed = bare.find_reference(&format!("refs/heads/{}", branch)).unwrap().target();
+        assert_eq!(pushed, repo.head().unwrap().target());
+    }
+
+    #[test]
+    fn test_push_without_upstream_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("file.txt"), "one\n").unwrap();
+        commit_all(&repo, "initial");
+
+        let config = GitConfig {
+            repo_path: dir.path().to_path_buf(),
+            ..GitConfig::default()
+        };
+        let result = GitRepo::new(config).unwrap().push();
+        assert!(matches!(result, Err(Error::NoUpstream(_))));
+    }
+
+    #[test_case(DiffAlgorithm::Myers)]
+    #[test_case(DiffAlgorithm::Minimal)]
+    #[test_case(DiffAlgorithm::Patience)]
+    #[test_case(DiffAlgorithm::Histogram)]
+    fn test_diff_algorithms_describe_the_same_change(algorithm: DiffAlgorithm) {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("file.txt"), "fn a() {}\n\nfn b() {}\n").unwrap();
+        commit_all(&repo, "initial");
+        fs::write(dir.path().join("file.txt"), "fn b() {}\n\nfn c() {}\n").unwrap();
+
+        let config = GitConfig {
+            repo_path: dir.path().to_path_buf(),
+            diff_algorithm: algorithm,
+            ..GitConfig::default()
+        };
+        let diff = GitRepo::new(config).unwrap().get_diff().unwrap();
+        let context = CommitContext::from_diff(&diff);
+
+        assert!(diff.contains("+fn c() {}"));
+        assert!(diff.contains("-fn a() {}"));
+        assert_eq!(context.total_additions, context.total_deletions);
+    }
+
+    #[test]
+    fn test_vendored_changes_are_summarized() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::create_dir_all(dir.path().join("vendor/lib")).unwrap();
+        fs::write(dir.path().join("vendor/lib/a.js"), "one\n").unwrap();
+        fs::write(dir.path().join("dist.js"), "one\n").unwrap();
+        fs::write(dir.path().join(".gitattributes"), "dist.js linguist-generated\n").unwrap();
+        commit_all(&repo, "initial");
+        fs::write(dir.path().join("vendor/lib/a.js"), "two\nthree\n").unwrap();
+        fs::write(dir.path().join("dist.js"), "two\n").unwrap();
+
+        let config = GitConfig {
+            repo_path: dir.path().to_path_buf(),
+            ..GitConfig::default()
+        };
+        let diff = GitRepo::new(config).unwrap().get_diff().unwrap();
+        let context = CommitContext::from_diff(&diff);
+
+        assert!(!diff.contains("three"));
+        assert_eq!(diff.matches(GENERATED_MARKER).count(), 2);
+        assert_eq!(context.generated_files, vec!["dist.js", "vendor/lib/a.js"]);
+        assert_eq!(context.get_suggested_type(), "chore");
+    }
+
+    #[test]
+    fn test_all_changes_excluded_is_reported_as_filtered() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("Cargo.lock"), "one\n").unwrap();
+        fs::write(dir.path().join(IGNORE_FILE), "*.snap\n").unwrap();
+        fs::write(dir.path().join("a.snap"), "one\n").unwrap();
+        commit_all(&repo, "initial");
+        fs::write(dir.path().join("Cargo.lock"), "two\n").unwrap();
+        fs::write(dir.path().join("a.snap"), "two\n").unwrap();
+
+        let config = GitConfig {
+            repo_path: dir.path().to_path_buf(),
+            exclude_paths: vec!["Cargo.lock".to_string()],
+            ..GitConfig::default()
+        };
+        match GitRepo::new(config).unwrap().get_diff() {
+            Err(Error::AllChangesFiltered(filters)) => assert_eq!(
+                filters,
+                vec!["Cargo.lock (exclude_paths)".to_string(), format!("*.snap ({})", IGNORE_FILE)]
+            ),
+            other => panic!("expected all changes to be filtered, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_diff_since_last_tag() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("file.txt"), "one\n").unwrap();
+        commit_all(&repo, "initial");
+        let config = GitConfig {
+            repo_path: dir.path().to_path_buf(),
+            ..GitConfig::default()
+        };
+        assert_eq!(GitRepo::new(config.clone()).unwrap().diff_since_last_tag().unwrap(), None);
+
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        repo.tag_lightweight("v1.0.0", head.as_object(), false).unwrap();
+        fs::write(dir.path().join("file.txt"), "one\ntwo\n").unwrap();
+        commit_all(&repo, "feat: add two");
+        fs::write(dir.path().join("file.txt"), "one\ntwo\nthree\n").unwrap();
+        commit_all(&repo, "feat: add three");
+
+        let (tag, diff) = GitRepo::new(config).unwrap().diff_since_last_tag().unwrap().unwrap();
+        assert_eq!(tag, "v1.0.0");
+        assert!(diff.contains("+two") && diff.contains("+three"));
+    }
+
+    #[test]
+    fn test_module_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        for path in ["src/main.rs", "src/config.rs", "src/auth/mod.rs", "src/auth/token.rs", "docs/guide.md", ".github/workflows/ci.yml", "README.md"] {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "x\n").unwrap();
+        }
+        commit_all(&repo, "initial");
+        let config = GitConfig {
+            repo_path: dir.path().to_path_buf(),
+            ..GitConfig::default()
+        };
+
+        let names = GitRepo::new(config).unwrap().module_names().unwrap();
+        assert_eq!(names, vec!["docs", "src/auth", "src/config"]);
+    }
+
+    #[test]
+    fn test_changed_files_come_from_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("kept.txt"), "one\n").unwrap();
+        fs::write(dir.path().join("removed.txt"), "one\n").unwrap();
+        commit_all(&repo, "initial");
+        fs::write(dir.path().join("kept.txt"), "two\n").unwrap();
+        fs::remove_file(dir.path().join("removed.txt")).unwrap();
+        fs::write(dir.path().join("new.txt"), "one\n").unwrap();
+        let config = GitConfig {
+            repo_path: dir.path().to_path_buf(),
+            ..GitConfig::default()
+        };
+
+        let mut files = GitRepo::new(config.clone()).unwrap().changed_files().unwrap();
+        files.sort();
+        assert_eq!(files, vec![
+            ("added", "new.txt".to_string()),
+            ("deleted", "removed.txt".to_string()),
+            ("modified", "kept.txt".to_string()),
+        ]);
+
+        let staged_only = GitConfig { staged_only: true, ..config };
+        assert_eq!(GitRepo::new(staged_only).unwrap().changed_files().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_stash_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("file.txt"), "one\n").unwrap();
+        commit_all(&repo, "initial");
+        let config = GitConfig {
+            repo_path: dir.path().to_path_buf(),
+            ..GitConfig::default()
+        };
+        assert!(matches!(
+            GitRepo::new(config.clone()).unwrap().stash_diff(0),
+            Err(Error::NoStash { index: 0, count: 0 })
+        ));
+
+        let signature = Signature::now("Test", "test@example.com").unwrap();
+        fs::write(dir.path().join("file.txt"), "one\nstashed\n").unwrap();
+        repo.stash_save(&signature, "first", None).unwrap();
+        fs::write(dir.path().join("file.txt"), "one\nnewer\n").unwrap();
+        repo.stash_save(&signature, "second", None).unwrap();
+
+        let git_repo = GitRepo::new(config).unwrap();
+        assert!(git_repo.stash_diff(0).unwrap().contains("+newer"));
+        assert!(git_repo.stash_diff(1).unwrap().contains("+stashed"));
+        assert!(matches!(git_repo.stash_diff(2), Err(Error::NoStash { index: 2, count: 2 })));
+    }
+
+    #[test]
+    fn test_commit_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("file.txt"), "one\n").unwrap();
+        commit_all(&repo, "initial");
+        fs::write(dir.path().join("file.txt"), "one\ntwo\n").unwrap();
+        commit_all(&repo, "second");
+        fs::write(dir.path().join("file.txt"), "one\ntwo\nuncommitted\n").unwrap();
+
+        let git_repo = GitRepo::new(GitConfig {
+            repo_path: dir.path().to_path_buf(),
+            ..GitConfig::default()
+        })
+        .unwrap();
+        let head = git_repo.commit_diff("HEAD").unwrap();
+        assert!(head.contains("+two") && !head.contains("+one") && !head.contains("uncommitted"));
+        let root = repo.head().unwrap().peel_to_commit().unwrap().parent_id(0).unwrap();
+        let root = git_repo.commit_diff(&root.to_string()[..7]).unwrap();
+        assert!(root.contains("new file") && root.contains("+one") && !root.contains("+two"));
+        assert!(matches!(git_repo.commit_diff("no-such-rev"), Err(Error::Git(_))));
+    }
+
+    #[test]
+    fn test_linked_worktree() {
+        let dir = tempfile::tempdir().unwrap();
+        let main_path = dir.path().join("main");
+        let repo = Repository::init(&main_path).unwrap();
+        let mut git_config = repo.config().unwrap();
+        git_config.set_str("user.name", "Test").unwrap();
+        git_config.set_str("user.email", "test@example.com").unwrap();
+        fs::write(main_path.join("file.txt"), "one\n").unwrap();
+        commit_all(&repo, "initial");
+        let main_head = repo.head().unwrap().target().unwrap();
+
+        let worktree_path = dir.path().join("feature");
+        repo.worktree("feature", &worktree_path, None).unwrap();
+        fs::write(worktree_path.join("file.txt"), "one\ntwo\n").unwrap();
+        let config = GitConfig {
+            repo_path: worktree_path.clone(),
+            ..GitConfig::default()
+        };
+        let git_repo = GitRepo::new(config).unwrap();
+        assert_eq!(git_repo.workdir().map(|p| p.canonicalize().unwrap()), Some(worktree_path.canonicalize().unwrap()));
+        assert!(git_repo.get_diff().unwrap().contains("+two"));
+        git_repo.commit("feat: add two").unwrap();
+
+        // The commit lands on the worktree's branch, the main checkout is untouched
+        let worktree_repo = Repository::open(&worktree_path).unwrap();
+        let head = worktree_repo.head().unwrap();
+        assert_eq!(head.shorthand(), Some("feature"));
+        assert_eq!(head.peel_to_commit().unwrap().message(), Some("feat: add two"));
+        assert_eq!(repo.head().unwrap().target().unwrap(), main_head);
+        assert_eq!(fs::read_to_string(main_path.join("file.txt")).unwrap(), "one\n");
+    }
+
+    #[test]
+    fn test_diff_leaves_staging_to_the_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let mut git_config = repo.config().unwrap();
+        git_config.set_str("user.name", "Test").unwrap();
+        git_config.set_str("user.email", "test@example.com").unwrap();
+        fs::write(dir.path().join("file.txt"), "one\n").unwrap();
+        let config = GitConfig {
+            repo_path: dir.path().to_path_buf(),
+            ..GitConfig::default()
+        };
+        let git_repo = GitRepo::new(config).unwrap();
+
+        // Computing the diff once per regeneration attempt stages nothing
+        for _ in 0..3 {
+            assert!(git_repo.get_diff().unwrap().contains("+one"));
+        }
+        assert_eq!(Repository::open(dir.path()).unwrap().index().unwrap().len(), 0);
+        assert!(git_repo.has_changes().unwrap());
+
+        git_repo.commit("feat: add file").unwrap();
+        let index = Repository::open(dir.path()).unwrap().index().unwrap();
+        assert_eq!(index.len(), 1);
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert!(head.tree().unwrap().get_name("file.txt").is_some());
+        assert_eq!(head.parent_count(), 0);
+    }
+}
@@ -1,18 +1,217 @@
 pub mod ai;
 pub mod config;
+pub mod conventional;
+pub mod diff;
 pub mod error;
 pub mod git;
 pub mod protocol;
+pub mod recording;
+pub mod ticket;
+pub mod trailer;
+pub mod validator;
 
-pub use crate::ai::AiClient;
-pub use crate::config::{Config, AiConfig, GitConfig, CommitConfig, AVAILABLE_MODELS};
+pub use crate::ai::{AiClient, CommitContext, Generated};
+pub use crate::conventional::{parse_conventional, ParsedCommit};
+pub use crate::config::{
+    Config, AiConfig, ClassificationThresholds, DiffAlgorithm, GitConfig, CommitConfig, MessageLength, PromptStyle, Provider,
+    TicketPlacement, UserSettings, AVAILABLE_MODELS,
+};
 pub use crate::error::{Error, Result};
 pub use crate::git::GitRepo;
+pub use crate::validator::{CommitValidator, validators};
+pub use tokio_util::sync::CancellationToken;
 pub use crate::protocol::{
-    ModelProvider, CommitMessageGenerator, ModelContext, GenerationConfig,
-    Message, TogetherAiProvider,
+    ModelProvider, CommitMessageGenerator, ModelContext, GenerationConfig, ProviderCapabilities,
+    Message, TogetherAiProvider, HuggingFaceProvider,
 };
 
+/// Checks if a commit message follows the Conventional Commits specification, with the
+/// types of the default `CommitConfig::allowed_types`
+pub fn is_conventional_commit(message: &str) -> bool {
+    is_conventional_commit_with_types(message, &CommitConfig::default().allowed_types)
+}
+
+/// Checks if a commit message follows the Conventional Commits format using only
+/// the given commit types, e.g. `CommitConfig::allowed_types`
+pub fn is_conventional_commit_with_types<S: AsRef<str>>(message: &str, allowed_types: &[S]) -> bool {
+    parse_conventional(message)
+        .is_some_and(|parsed| allowed_types.iter().any(|t| t.as_ref() == parsed.commit_type))
+}
+
+/// Runs the configured validator chain against a commit message and returns the problems
+/// found. Only the subject line is checked, except by the custom pattern; an empty result
+/// means the message is valid.
+pub fn validate_message(message: &str, config: &CommitConfig) -> Result<Vec<String>> {
+    // A `PROJ-123 feat: ...` prefix is allowed in front of the conventional subject
+    let message = if config.ticket_placement == TicketPlacement::Prefix {
+        ticket::strip_ticket_prefix(message, &config.ticket_pattern)?
+    } else {
+        message
+    };
+    let message = if config.gitmoji { ai::strip_leading_emoji(message) } else { message };
+    Ok(validator::run_validators(&validators(config)?, message))
+}
+
+/// Hard-wraps body text to `width` columns. Blank lines are kept, and continuation lines
+/// of bullets (`- `, `* `, `1. `) are indented to line up with the bullet's text.
+pub fn wrap_body(text: &str, width: usize) -> String {
+    let mut wrapped = Vec::new();
+    for line in text.lines() {
+        let content = line.trim_start();
+        if content.is_empty() {
+            wrapped.push(String::new());
+            continue;
+        }
+
+        let indent = &line[..line.len() - content.len()];
+        let marker_len = bullet_marker_len(content);
+        let first_prefix = format!("{}{}", indent, &content[..marker_len]);
+        let continuation = " ".repeat(first_prefix.chars().count());
+
+        let mut current = first_prefix;
+        let mut has_words = false;
+        for word in content[marker_len..].split_whitespace() {
+            if has_words && current.chars().count() + 1 + word.chars().count() > width {
+                wrapped.push(std::mem::replace(&mut current, continuation.clone()));
+                has_words = false;
+            }
+            if has_words {
+                current.push(' ');
+            }
+            current.push_str(word);
+            has_words = true;
+        }
+        wrapped.push(current);
+    }
+    wrapped.join("\n")
+}
+
+/// Length of a leading `- `, `* `, `+ ` or `1. ` list marker, or 0
+fn bullet_marker_len(line: &str) -> usize {
+    if ["- ", "* ", "+ "].iter().any(|marker| line.starts_with(marker)) {
+        return 2;
+    }
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    if digits > 0 && line[digits..].starts_with(". ") {
+        return digits + 2;
+    }
+    0
+}
+
+/// Wraps the body of a message (everything below the subject) to `body_wrap_width` and
+/// caps it at `max_body_lines`, leaving the subject untouched
+pub fn format_body(message: &str, config: &CommitConfig) -> String {
+    let Some((subject, body)) = message.split_once('\n') else {
+        return message.to_string();
+    };
+    let body = wrap_body(body.trim_matches('\n'), config.body_wrap_width);
+    let mut lines: Vec<&str> = body.lines().collect();
+    if let Some(max) = config.max_body_lines {
+        lines.truncate(max);
+    }
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+    if lines.is_empty() {
+        return subject.to_string();
+    }
+    format!("{}\n\n{}", subject, lines.join("\n"))
+}
+
+/// Non-blank lines of the body of a message (everything below the subject)
+pub fn body_lines(message: &str) -> Vec<&str> {
+    message.lines().skip(1).filter(|line| !line.trim().is_empty()).collect()
+}
+
+/// Keeps the subject and the body lines whose flag in `keep` is set, in order. Blank lines
+/// separating kept lines, e.g. before footers, are kept too.
+pub fn select_body_lines(message: &str, keep: &[bool]) -> String {
+    let subject = message.lines().next().unwrap_or("");
+    let mut keep = keep.iter();
+    let mut body: Vec<&str> = Vec::new();
+    for line in message.lines().skip(1) {
+        if line.trim().is_empty() {
+            if body.last().is_some_and(|last| !last.is_empty()) {
+                body.push("");
+            }
+        } else if keep.next().copied().unwrap_or(false) {
+            body.push(line);
+        }
+    }
+    if body.last() == Some(&"") {
+        body.pop();
+    }
+    if body.is_empty() {
+        return subject.to_string();
+    }
+    format!("{}\n\n{}", subject, body.join("\n"))
+}
+
+/// Flips the flags of the 1-based line numbers in `input`, e.g. `2 4` or `2,4`; anything
+/// that isn't a line number is ignored
+pub fn toggle_lines(keep: &mut [bool], input: &str) {
+    for number in input.split(|c: char| c.is_whitespace() || c == ',') {
+        if let Some(flag) = number.parse::<usize>().ok().and_then(|n| keep.get_mut(n.wrapping_sub(1))) {
+            *flag = !*flag;
+        }
+    }
+}
+
+/// Checks if the description of a commit message starts with an imperative verb
+/// ("add" rather than "added", "adds" or "adding")
+pub fn is_imperative_mood(message: &str) -> bool {
+    // Imperative verbs that happen to end like past tense, gerunds or third person
+    const ALLOWLIST: [&str; 16] = [
+        "embed", "feed", "seed", "shed", "speed", "proceed", "exceed", "succeed",
+        "bring", "ping", "ring", "spring", "string", "swing", "alias", "canvas",
+    ];
+
+    let subject = message.lines().next().unwrap_or("");
+    let description = subject.split_once(": ").map_or(subject, |(_, d)| d);
+    let first_word = description
+        .split_whitespace()
+        .next()
+        .unwrap_or("")
+        .trim_matches(|c: char| !c.is_alphanumeric())
+        .to_lowercase();
+
+    if ALLOWLIST.contains(&first_word.as_str()) {
+        return true;
+    }
+    // Words ending in "ss", "us" or "is" (address, focus, analysis) aren't third person
+    let third_person = first_word.ends_with('s')
+        && !["ss", "us", "is"].iter().any(|suffix| first_word.ends_with(suffix));
+    !(first_word.ends_with("ed") || first_word.ends_with("ing") || third_person)
+}
+
+/// Puts a message above the existing content of a commit message file, such as the
+/// comment block git adds or the text of a `commit.template`
+pub fn prepend_to_editmsg(existing: &str, message: &str) -> String {
+    match existing.trim_start_matches('\n') {
+        "" => format!("{}\n", message),
+        existing => format!("{}\n\n{}", message, existing),
+    }
+}
+
+/// Answer to the prompt asking whether to commit a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Confirmation {
+    Yes,
+    No,
+    Reword,
+}
+
+/// Reads an answer to the commit prompt; empty input picks `default_yes`, anything
+/// unrecognised declines
+pub fn parse_confirmation(input: &str, default_yes: bool) -> Confirmation {
+    match input.trim().to_lowercase().as_str() {
+        "" if default_yes => Confirmation::Yes,
+        "y" | "yes" => Confirmation::Yes,
+        "r" | "reword" => Confirmation::Reword,
+        _ => Confirmation::No,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -21,33 +220,155 @@ mod tests {
     #[test_case("feat: add new feature", true)]
     #[test_case("fix(core): resolve issue", true)]
     #[test_case("random message", false)]
+    #[test_case("feat(): add x", false)]
+    #[test_case("feat( ): add x", false)]
+    #[test_case("feat(a): add x", true)]
     fn test_is_conventional_commit(message: &str, expected: bool) {
         let is_conventional = is_conventional_commit(message);
         assert_eq!(is_conventional, expected);
     }
-}
 
-/// Checks if a commit message follows the Conventional Commits specification
-pub fn is_conventional_commit(message: &str) -> bool {
-    let conventional_types = [
-        "feat", "fix", "docs", "style", "refactor",
-        "perf", "test", "build", "ci", "chore", "revert"
-    ];
+    #[test]
+    fn test_custom_type_is_accepted_end_to_end() {
+        let mut config = CommitConfig::default();
+        config.allowed_types.push("hotfix".to_string());
+        let message = "hotfix(api): restore session refresh";
+
+        let provider = TogetherAiProvider::new(String::new(), "m".to_string())
+            .with_allowed_types(config.allowed_types.clone());
+        assert!(provider.allowed_types().contains(&"hotfix".to_string()));
+        assert!(CommitMessageGenerator::validate_message(&provider, message));
+        assert!(is_conventional_commit_with_types(message, &config.allowed_types));
+        assert_eq!(validate_message(message, &config).unwrap(), Vec::<String>::new());
 
-    // Basic format: <type>[optional scope]: <description>
-    let parts: Vec<&str> = message.splitn(2, ": ").collect();
-    if parts.len() != 2 {
-        return false;
+        assert!(!is_conventional_commit(message));
+        assert!(!validate_message(message, &CommitConfig::default()).unwrap().is_empty());
     }
 
-    let type_part = parts[0];
-    
-    // Check if there's a scope
-    let commit_type = if type_part.contains('(') {
-        type_part.split('(').next().unwrap_or("")
-    } else {
-        type_part
-    };
+    #[test]
+    fn test_together_provider_base_url() {
+        let provider = TogetherAiProvider::new(String::new(), "m".to_string());
+        assert_eq!(provider.chat_url(), "https://api.together.xyz/v1/chat/completions");
+        let provider = provider.with_base_url("http://localhost:8080/v1".to_string());
+        assert_eq!(provider.chat_url(), "http://localhost:8080/v1/chat/completions");
+    }
+
+    #[test]
+    fn test_provider_capabilities() {
+        let together = TogetherAiProvider::new(String::new(), "mistralai/Mixtral-8x7B-Instruct-v0.1".to_string());
+        assert!(together.capabilities().json_mode && together.capabilities().streaming);
+        assert_eq!(together.capabilities().max_context_tokens, 32_768);
+        let llama = TogetherAiProvider::new(String::new(), "meta-llama/Llama-2-70b-chat-hf".to_string());
+        assert_eq!(llama.capabilities().max_context_tokens, 4096);
+
+        let huggingface = HuggingFaceProvider::new(String::new(), "gpt2".to_string());
+        assert_eq!(huggingface.capabilities(), ProviderCapabilities::CONSERVATIVE);
+    }
+
+    #[test]
+    fn test_configured_allowed_types_reject_docs() {
+        let config = CommitConfig {
+            allowed_types: vec!["feat".to_string(), "fix".to_string(), "chore".to_string()],
+            ..CommitConfig::default()
+        };
+        assert!(is_conventional_commit_with_types("fix: handle empty diff", &config.allowed_types));
+        assert!(!is_conventional_commit_with_types("docs: update readme", &config.allowed_types));
+    }
 
-    conventional_types.contains(&commit_type)
-} 
\ No newline at end of file
+    #[test]
+    fn test_validate_message_collects_problems() {
+        let config = CommitConfig {
+            allowed_scopes: vec!["api".to_string()],
+            max_length: 20,
+            ..CommitConfig::default()
+        };
+        assert!(validate_message("feat(api): add x", &config).unwrap().is_empty());
+        assert_eq!(validate_message("feat(ui): add a rather long thing", &config).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_wrap_body_long_paragraph() {
+        let text = "This change moves token refresh into the background so requests never block on it.";
+        assert_eq!(
+            wrap_body(text, 30),
+            "This change moves token\nrefresh into the background so\nrequests never block on it."
+        );
+    }
+
+    #[test]
+    fn test_wrap_body_keeps_bullets_and_blank_lines() {
+        let text = "- refresh tokens in the background\n\n12. retry failed refreshes twice";
+        assert_eq!(
+            wrap_body(text, 20),
+            "- refresh tokens in\n  the background\n\n12. retry failed\n    refreshes twice"
+        );
+    }
+
+    #[test]
+    fn test_format_body_caps_lines() {
+        let config = CommitConfig {
+            body_wrap_width: 20,
+            max_body_lines: Some(2),
+            ..CommitConfig::default()
+        };
+        let message = "feat: add x\n\n- one two three four five six\n- seven";
+        assert_eq!(format_body(message, &config), "feat: add x\n\n- one two three four\n  five six");
+    }
+
+    #[test_case("feat: add x", true)]
+    #[test_case("feat: added x", false)]
+    #[test_case("fix(core): adds x", false)]
+    #[test_case("fix: fixing x", false)]
+    #[test_case("refactor: address review comments", true)]
+    #[test_case("feat: embed assets", true)]
+    fn test_is_imperative_mood(message: &str, expected: bool) {
+        assert_eq!(is_imperative_mood(message), expected);
+    }
+
+    #[test_case("", "feat: add x\n"; "empty file")]
+    #[test_case("\n# Please enter the commit message\n", "feat: add x\n\n# Please enter the commit message\n"; "git comment block")]
+    #[test_case("Ticket: \n# template\n", "feat: add x\n\nTicket: \n# template\n"; "commit template")]
+    fn test_prepend_to_editmsg(existing: &str, expected: &str) {
+        assert_eq!(prepend_to_editmsg(existing, "feat: add x"), expected);
+    }
+
+    #[test]
+    fn test_select_body_lines() {
+        let message = "feat: add login\n\n- add form\n- add session store\n\n- log attempts";
+        assert_eq!(body_lines(message), vec!["- add form", "- add session store", "- log attempts"]);
+        assert_eq!(
+            select_body_lines(message, &[true, false, true]),
+            "feat: add login\n\n- add form\n\n- log attempts"
+        );
+        assert_eq!(select_body_lines(message, &[false, false, false]), "feat: add login");
+    }
+
+    #[test]
+    fn test_select_body_lines_keeps_footer_separator() {
+        let message = "fix: handle empty input\n\n- check for empty diffs\n- skip the API call\n\nCloses #12";
+        assert_eq!(
+            select_body_lines(message, &[true, false, true]),
+            "fix: handle empty input\n\n- check for empty diffs\n\nCloses #12"
+        );
+        assert_eq!(select_body_lines(message, &[false, false, true]), "fix: handle empty input\n\nCloses #12");
+    }
+
+    #[test_case("2", vec![true, false, true]; "one line")]
+    #[test_case("1, 3", vec![false, true, false]; "several lines")]
+    #[test_case("2 2", vec![true, true, true]; "toggled twice")]
+    #[test_case("0 4 x", vec![true, true, true]; "out of range")]
+    fn test_toggle_lines(input: &str, expected: Vec<bool>) {
+        let mut keep = vec![true; 3];
+        toggle_lines(&mut keep, input);
+        assert_eq!(keep, expected);
+    }
+
+    #[test_case("\n", false, Confirmation::No)]
+    #[test_case("\n", true, Confirmation::Yes)]
+    #[test_case("n\n", true, Confirmation::No)]
+    #[test_case("Y\n", false, Confirmation::Yes)]
+    #[test_case("r\n", true, Confirmation::Reword)]
+    fn test_parse_confirmation(input: &str, default_yes: bool, expected: Confirmation) {
+        assert_eq!(parse_confirmation(input, default_yes), expected);
+    }
+}
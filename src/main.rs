@@ -1,35 +1,179 @@
-use std::path::PathBuf;
-use clap::Parser;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use clap::{Parser, Subcommand, ValueEnum};
+use futures::StreamExt;
 use git_commit_sage::{
-    AiClient, GitRepo, Config, Error, Result, AVAILABLE_MODELS,
-    is_conventional_commit,
+    AiClient, CommitContext, DiffAlgorithm, GitRepo, Config, Error, MessageLength, Result, AVAILABLE_MODELS,
+    GenerationConfig, HuggingFaceProvider, Message, ModelContext, ModelProvider, Provider,
+    TogetherAiProvider, UserSettings, config::{ENV_MODEL, TAG_CHAT, TAG_CODING},
+    format_body, body_lines, select_body_lines, toggle_lines, prepend_to_editmsg, is_conventional_commit_with_types, is_imperative_mood, protocol::conversion_context, validate_message, wrap_body, ai::{issue_footers, strip_leading_emoji},
+    git::parse_commit_date, validators, validator::{length_warning, run_validators},
+    ticket::{apply_ticket, strip_ticket_prefix, ticket_from_branch},
+    trailer::{assemble_trailers, has_trailer, parse_trailers}, recording,
+    diff::{conflict_marker_files, count_tokens, group_by_area, split_by_file, split_for_summary}, parse_conventional, parse_confirmation, Confirmation,
+    conventional::{breaking_change_footer, mark_breaking, normalize_description, structure_violations},
 };
 use tracing::{info, warn};
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Write};
+use notify::{RecursiveMode, Watcher};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+/// Output format for the generated message
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    /// Human-readable text
+    Text,
+    /// JSON object with the message, the diff and its parsed context
+    Json,
+}
+
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0    Success
+  1    Other error (git, IO, configuration)
+  2    No changes to commit
+  3    API key not provided
+  4    Commit message generation or validation failed
+  5    Network or API error
+  130  Interrupted";
 
 /// A smart Git commit message generator using AI
 #[derive(Parser, Debug)]
-#[command(author, version, about)]
+#[command(author, version, about, after_help = EXIT_CODES_HELP)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Path to the git repository (defaults to current directory)
     #[arg(short, long)]
     path: Option<PathBuf>,
 
-    /// Together.ai API key
-    #[arg(short = 'k', long, env = "TOGETHER_API_KEY")]
+    /// API key [default: TOGETHER_API_KEY, or HF_TOKEN for Hugging Face]
+    #[arg(short = 'k', long)]
     api_key: Option<String>,
 
+    /// Base URL of the Together.ai API, e.g. a regional endpoint or a proxy
+    #[arg(long, value_name = "URL")]
+    api_base: Option<String>,
+
+    /// API provider
+    #[arg(long, value_enum)]
+    provider: Option<Provider>,
+
     /// AI model to use
     #[arg(short, long)]
     model: Option<String>,
 
-    /// Temperature for model output (0.0 to 1.0)
-    #[arg(short = 't', long, default_value = "0.3")]
-    temperature: f32,
+    /// Temperature for model output (0.0 to 1.0) [default: provider's recommendation]
+    #[arg(short = 't', long)]
+    temperature: Option<f32>,
+
+    /// Maximum tokens in response [default: provider's recommendation]
+    #[arg(long)]
+    max_tokens: Option<u32>,
+
+    /// Drop comment-only line changes from the diff sent to the model
+    #[arg(long)]
+    strip_comments: bool,
+
+    /// Message verbosity
+    #[arg(long, value_enum)]
+    length: Option<MessageLength>,
+
+    /// Diff algorithm for the diff sent to the model
+    #[arg(long, value_enum)]
+    diff_algorithm: Option<DiffAlgorithm>,
+
+    /// Only describe and commit changes already staged in the index
+    #[arg(long)]
+    staged: bool,
+
+    /// Include the previous commit's diff as context, e.g. for fixes of a fresh regression
+    #[arg(long)]
+    with_prev: bool,
+
+    /// Commit the existing index as-is instead of staging all changes first
+    #[arg(long)]
+    no_stage: bool,
+
+    /// Proceed even though added lines contain merge conflict markers
+    #[arg(long)]
+    force: bool,
+
+    /// Always call the model, even for diffs below `min_diff_lines`
+    #[arg(long)]
+    force_ai: bool,
+
+    /// Keep the type and scope of this message and only reword its description
+    #[arg(long, value_name = "MESSAGE")]
+    reword: Option<String>,
+
+    /// Author and committer date for the commit (RFC 3339, e.g. 2024-01-31T12:00:00+01:00)
+    #[arg(long, value_name = "RFC3339")]
+    date: Option<String>,
+
+    /// Add the issue key from the branch name, e.g. `PROJ-123 feat: ...` (see ticket_placement)
+    #[arg(long)]
+    prepend_ticket: bool,
+
+    /// File with project context for the model, e.g. domain terms and scope conventions
+    #[arg(long, value_name = "PATH")]
+    context_file: Option<PathBuf>,
+
+    /// Why the change was made, woven into the generated body
+    #[arg(long, value_name = "TEXT")]
+    reason: Option<String>,
+
+    /// Use this message instead of generating one (still validated)
+    #[arg(short = 'M', long)]
+    message: Option<String>,
+
+    /// Skip the model and use the configured WIP message, for quick checkpoint commits
+    #[arg(long, conflicts_with_all = ["message", "reword"])]
+    wip: bool,
+
+    /// Allow committing with no changes, e.g. to trigger CI (requires --message)
+    #[arg(long, requires = "message")]
+    allow_empty: bool,
+
+    /// Suggest a message for each changed file separately, without staging or committing
+    #[arg(long)]
+    per_file: bool,
+
+    /// Summarize everything since the most recent tag, e.g. for a tag annotation (never commits)
+    #[arg(long, conflicts_with_all = ["auto_commit", "per_file"])]
+    since_last_tag: bool,
+
+    /// Write the diff as sent to the API, after filtering, comment stripping and truncation
+    #[arg(long, value_name = "PATH")]
+    dump_sent_diff: Option<PathBuf>,
+
+    /// Describe the stash entry stash@{N} against the commit it was made on (never commits)
+    #[arg(long, value_name = "INDEX", conflicts_with_all = ["auto_commit", "per_file", "since_last_tag"])]
+    stash: Option<usize>,
+
+    /// Suggest a replacement message for an existing commit from its diff against its
+    /// parent, e.g. with `git rebase --exec` (prints the message, never commits)
+    #[arg(long, value_name = "REV", conflicts_with_all = ["auto_commit", "per_file", "since_last_tag", "stash"])]
+    rev: Option<String>,
+
+    /// Maximum number of concurrent API requests with --per-file, split, bench and
+    /// hierarchical_summary
+    #[arg(long, alias = "jobs", global = true, default_value = "4", value_parser = clap::value_parser!(u16).range(1..))]
+    concurrency: u16,
+
+    /// Don't call the API; build a best-effort message from diff heuristics
+    #[arg(long)]
+    offline: bool,
+
+    /// Fall back to a heuristic message when the API is unreachable
+    #[arg(long)]
+    allow_heuristic_fallback: bool,
 
-    /// Maximum tokens in response
-    #[arg(long, default_value = "100")]
-    max_tokens: u32,
+    /// Mark the change as breaking, with this text as its `BREAKING CHANGE:` footer
+    #[arg(long, value_name = "TEXT")]
+    breaking_description: Option<String>,
 
     /// Include untracked files in diff
     #[arg(short, long)]
@@ -47,51 +191,235 @@ struct Args {
     #[arg(long)]
     no_verify: bool,
 
+    /// Fail validation when the subject is longer than recommended_length (50 by default)
+    /// instead of only warning
+    #[arg(long)]
+    strict_length: bool,
+
+    /// Push the current branch to its upstream after committing
+    #[arg(long, requires = "auto_commit")]
+    push: bool,
+
     /// Skip user confirmation
     #[arg(short = 'y', long)]
     yes: bool,
 
+    /// Pick which lines of a generated body to keep before committing
+    #[arg(short = 'i', long)]
+    interactive: bool,
+
+    /// Body text appended below the generated subject
+    #[arg(long, conflicts_with = "body_file")]
+    body: Option<String>,
+
+    /// Read the commit body from a file
+    #[arg(long, value_name = "PATH")]
+    body_file: Option<PathBuf>,
+
+    /// File of `Token: value` trailers (e.g. `Reviewed-by`) to add below the body
+    #[arg(long, value_name = "PATH")]
+    trailers_file: Option<PathBuf>,
+
     /// Path to custom configuration file
     #[arg(short = 'f', long)]
     config: Option<PathBuf>,
 
+    /// Extra HTTP header for API requests (repeatable)
+    #[arg(long = "header", value_name = "KEY=VALUE", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+
+    /// Extra field for the API request body, the value parsed as JSON if possible (repeatable)
+    #[arg(long = "param", value_name = "KEY=VALUE", value_parser = parse_param)]
+    params: Vec<(String, serde_json::Value)>,
+
+    /// Also write the generated message to a file (e.g. .git/COMMIT_EDITMSG)
+    #[arg(short = 'o', long, value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    /// Insert the generated message above the existing content of a commit message file,
+    /// keeping git's comment block or a commit template (e.g. from a prepare-commit-msg hook)
+    #[arg(long, value_name = "PATH")]
+    append_to_editmsg: Option<PathBuf>,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// Don't print the pre-flight diff summary
+    #[arg(short, long)]
+    quiet: bool,
+
     /// List available models
     #[arg(short, long)]
     list_models: bool,
 
+    /// With --list-models, only list models suited to code
+    #[arg(long, requires = "list_models")]
+    coding: bool,
+
+    /// With --list-models, only list chat-tuned models
+    #[arg(long, requires = "list_models")]
+    chat: bool,
+
+    /// Also append debug-level logs to a file
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Write the exact API requests and responses to a file, e.g. for audits
+    #[arg(long, value_name = "PATH", conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Answer API requests with the responses recorded by --record, without calling the API
+    #[arg(long, value_name = "PATH")]
+    replay: Option<PathBuf>,
+
+    /// Log the full API requests and responses (credentials masked); implies --debug
+    #[arg(long)]
+    trace_api: bool,
+
     /// Enable debug logging
     #[arg(short, long)]
     debug: bool,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Validate a commit message against the configured rules without generating one
+    Check {
+        /// The commit message to validate
+        message: String,
+    },
+    /// Rewrite a free-form message into conventional form and print it (no diff needed)
+    Convert {
+        /// The message to rewrite, e.g. from a legacy commit
+        message: String,
+    },
+    /// Check that the repository, API key and provider are usable
+    Doctor,
+    /// Watch the working tree and print an updated suggestion on every change (never commits)
+    Watch(WatchArgs),
+    /// Propose how to split the working tree into several commits, one per area (never commits)
+    Split(SplitArgs),
+    /// Run several models over the current diff and compare their suggestions and latency
+    /// (never commits)
+    Bench(BenchArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct BenchArgs {
+    /// Comma-separated model IDs to compare
+    #[arg(long, value_delimiter = ',', required = true)]
+    models: Vec<String>,
+
+    /// Minimum delay between the starts of two API calls, in milliseconds (also bounded by --concurrency)
+    #[arg(long, default_value = "1000")]
+    min_interval_ms: u64,
+}
+
+#[derive(clap::Args, Debug)]
+struct SplitArgs {
+    /// Print the plan as a shell script of `git add` and `git commit` lines
+    #[arg(long)]
+    script: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct WatchArgs {
+    /// Quiet period to wait for after the last file change, in milliseconds
+    #[arg(long, default_value = "500")]
+    debounce_ms: u64,
+
+    /// Minimum delay between two API calls, in seconds
+    #[arg(long, default_value = "10")]
+    min_interval: u64,
+}
+
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("Error: {}", e);
+        std::process::exit(exit_code(&e));
+    }
+}
+
+/// Maps an error to the exit code documented in `EXIT_CODES_HELP`
+fn exit_code(error: &Error) -> i32 {
+    match error {
+        Error::NoChanges => 2,
+        Error::NoApiKey => 3,
+        Error::CommitMessageGeneration(_)
+        | Error::InvalidMessage(_)
+        | Error::ValidationExhausted { .. } => 4,
+        Error::Request(_) | Error::ApiResponse { .. } | Error::InvalidResponse(_) => 5,
+        Error::Cancelled => 130,
+        _ => 1,
+    }
+}
+
+async fn run() -> Result<()> {
     // Load environment variables
     dotenvy::dotenv().ok();
 
     // Parse command line arguments
-    let args = Args::parse();
+    let mut args = Args::parse();
 
     // List available models if requested
     if args.list_models {
+        let required: Vec<&str> = [(args.coding, TAG_CODING), (args.chat, TAG_CHAT)]
+            .into_iter()
+            .filter_map(|(wanted, tag)| wanted.then_some(tag))
+            .collect();
         println!("Available models:");
-        for (model, description) in AVAILABLE_MODELS {
-            println!("  {} - {}", model, description);
+        for (model, description, tags) in AVAILABLE_MODELS {
+            if required.iter().all(|tag| tags.contains(tag)) {
+                println!("  {} - {} [{}]", model, description, tags.join(", "));
+            }
         }
         return Ok(());
     }
 
     // Setup logging
-    setup_logging(args.debug);
+    let _log_guard = setup_logging(args.debug || args.trace_api, args.log_file.as_deref())?;
 
-    // Load configuration
-    let mut config = if let Some(config_path) = args.config {
-        info!("Loading configuration from {}", config_path.display());
-        let config_str = std::fs::read_to_string(config_path)?;
-        toml::from_str(&config_str)?
-    } else {
-        Config::default()
-    };
+    // Load configuration: the global file first, then the project's on top of it
+    let config_paths: Vec<PathBuf> = Config::global_path()
+        .filter(|path| path.is_file())
+        .into_iter()
+        .chain(args.config)
+        .collect();
+    for path in &config_paths {
+        info!("Loading configuration from {}", path.display());
+    }
+    let config_from_file = !config_paths.is_empty();
+    let mut config = Config::load_layers(&config_paths)?;
+
+    // Without a config file, use the model remembered from the first run, or ask for one
+    if !config_from_file {
+        let mut settings = UserSettings::load()?;
+        let model_from_elsewhere = args.model.is_some() || std::env::var_os(ENV_MODEL).is_some();
+        if settings.model.is_none() && !model_from_elsewhere && is_interactive()
+            && !matches!(args.command, Some(Command::Check { .. }) | Some(Command::Convert { .. }))
+        {
+            settings.model = Some(choose_model()?);
+            let path = settings.save()?;
+            println!("Saved your choice to {}", path.display());
+        }
+        if let Some(model) = settings.model {
+            config.ai.model = model;
+        }
+    }
+    if let Some(provider) = args.provider {
+        config.ai.provider = provider;
+    }
+    if let Some(base_url) = args.api_base.take() {
+        config.ai.api_base_url = base_url;
+    }
+
+    // Generation settings no file sets come from the selected provider
+    config.ai.apply_provider_defaults();
+
+    // Environment variables override the file, command line arguments override both
+    config.apply_env_overrides()?;
 
     // Override configuration with command line arguments
     if let Some(path) = args.path {
@@ -100,88 +428,986 @@ async fn main() -> Result<()> {
     if let Some(model) = args.model {
         config.ai.model = model;
     }
-    config.ai.temperature = args.temperature;
-    config.ai.max_tokens = args.max_tokens;
+    config.ai.extra_headers.extend(args.headers);
+    config.ai.extra_params.extend(args.params);
+    config.ai.strip_comments |= args.strip_comments;
+    config.commit.ticket_from_branch |= args.prepend_ticket;
+    config.git.staged_only |= args.staged;
+    config.git.auto_stage &= !args.no_stage;
+    if let Some(algorithm) = args.diff_algorithm {
+        config.git.diff_algorithm = algorithm;
+    }
     config.git.include_untracked = args.untracked;
     config.git.show_diff = args.show_diff;
     config.commit.auto_commit = args.auto_commit;
     config.commit.verify_format = !args.no_verify;
+    config.commit.strict_length |= args.strict_length;
     config.commit.require_confirmation = !args.yes;
 
+    // Turn off features the provider lacks up front rather than failing mid-request
+    let capabilities = match config.ai.provider {
+        Provider::Together => TogetherAiProvider::new(String::new(), config.ai.model.clone()).capabilities(),
+        Provider::HuggingFace => HuggingFaceProvider::new(String::new(), config.ai.model.clone()).capabilities(),
+    };
+    if config.ai.structured_output && !capabilities.json_mode {
+        warn!("{} does not support JSON mode, ignoring structured_output", config.ai.provider);
+        config.ai.structured_output = false;
+    }
+
+    // A WIP checkpoint goes through the same path as a message given with --message
+    if args.wip {
+        args.message = Some(config.commit.wip_message.clone());
+    }
+
+    // Watch and split run once the repository and client are set up
+    let command = match args.command {
+        Some(Command::Check { message }) => return check_message(&message, &config),
+        Some(Command::Convert { message }) => return convert(&message, &config, args.api_key).await,
+        Some(Command::Doctor) => return doctor(&config, args.api_key).await,
+        command => command,
+    };
+
+    // Load the manual commit body, if any
+    let body = match (args.body, args.body_file) {
+        (Some(body), _) => Some(body),
+        (None, Some(body_path)) => Some(std::fs::read_to_string(body_path)?),
+        (None, None) => None,
+    };
+    let trailers = match &args.trailers_file {
+        Some(path) => parse_trailers(&std::fs::read_to_string(path)?)?,
+        None => Vec::new(),
+    };
+
     info!("Opening git repository at {}", config.git.repo_path.display());
     
     // Initialize git repository
-    let repo = GitRepo::new(config.git.clone())?;
+    let mut repo = GitRepo::new(config.git.clone())?;
+    if let Some(date) = &args.date {
+        repo = repo.with_commit_time(parse_commit_date(date)?);
+    }
 
-    // Check for changes
-    if !repo.has_changes()? {
+    // Check for changes (watch mode waits for them instead)
+    let describes_history = args.since_last_tag
+        || args.stash.is_some()
+        || args.rev.is_some()
+        || matches!(command, Some(Command::Watch(_)));
+    if !describes_history && !args.allow_empty && !repo.has_changes()? {
         warn!("No changes to commit!");
         return Err(Error::NoChanges);
     }
 
     // Get API key
     let api_key = args.api_key
-        .or_else(|| std::env::var("TOGETHER_API_KEY").ok())
-        .ok_or_else(|| Error::NoApiKey)?;
+        .or_else(|| std::env::var(config.ai.provider.api_key_env()).ok());
+    // A message given on the command line needs no API call
+    let api_key = match api_key {
+        Some(api_key) => api_key,
+        None if args.message.is_some() || args.offline || args.replay.is_some() => String::new(),
+        None => return Err(Error::NoApiKey),
+    };
+
+    if let Some(length) = args.length {
+        config.ai.length = length;
+    }
+    // A rationale goes in the body, so explaining why asks for one, as do release summaries
+    let explain_why = config.commit.explain_why || args.reason.is_some();
+    config.ai.include_body |= explain_why || args.since_last_tag;
+    config.ai.length.apply(&mut config.ai);
+    if explain_why && !config.ai.include_body {
+        warn!("Short messages have no body, ignoring explain_why and --reason");
+    }
+    // Bodies need more room than a subject, unless the user chose the budget
+    if args.max_tokens.is_none() {
+        config.ai.reserve_body_tokens();
+    }
+    if let Some(temperature) = args.temperature {
+        config.ai.temperature = temperature;
+    }
+    if let Some(max_tokens) = args.max_tokens {
+        config.ai.max_tokens = max_tokens;
+    }
+    if args.message.is_none() {
+        config.validate()?;
+    }
 
     // Initialize AI client
-    let ai_client = AiClient::new(api_key, config.ai.clone());
+    let mut ai_client = AiClient::new(api_key, config.ai.clone())
+        .with_allowed_types(config.commit.allowed_types.clone())
+        .with_trust_model_type(config.commit.trust_model_type)
+        .with_gitmoji(config.commit.gitmoji)
+        .with_api_trace(args.trace_api)
+        .with_retry_notes(!args.quiet && args.format == OutputFormat::Text);
+    if let Some(workdir) = repo.workdir() {
+        ai_client = ai_client.with_repo_root(workdir.to_path_buf());
+    }
+    if explain_why {
+        ai_client = ai_client.with_explain_why(args.reason.clone());
+    }
+    if let Some(path) = &args.dump_sent_diff {
+        ai_client = ai_client.with_sent_diff_dump(path.clone());
+    }
+    if let Some(path) = &args.record {
+        ai_client = ai_client.with_recording(path.clone());
+    }
+    if let Some(path) = &args.replay {
+        ai_client = ai_client.with_replay(recording::load(path)?);
+    }
+    if let Some(context_file) = &args.context_file {
+        ai_client = ai_client.with_project_context(std::fs::read_to_string(context_file)?);
+    }
+    if config.ai.include_file_list {
+        ai_client = ai_client.with_file_list(repo.changed_files()?);
+    }
+    if config.commit.include_tree_context {
+        ai_client = ai_client.with_tree_context(repo.module_names()?);
+    }
+    if args.with_prev {
+        match repo.last_commit_diff()? {
+            Some(previous) => ai_client = ai_client.with_previous_diff(previous),
+            None => warn!("No previous commit, ignoring --with-prev"),
+        }
+    }
+
+    match command {
+        Some(Command::Watch(watch_args)) => return watch(&repo, &ai_client, &config, watch_args).await,
+        Some(Command::Split(split_args)) => {
+            let limiter = Limiter::new(args.concurrency.into(), Duration::ZERO);
+            return split(&repo, &ai_client, split_args, &limiter).await;
+        }
+        Some(Command::Bench(bench_args)) => {
+            let limiter = Limiter::new(args.concurrency.into(), Duration::from_millis(bench_args.min_interval_ms));
+            return bench(&repo, &ai_client, bench_args, &limiter, args.format).await;
+        }
+        _ => {}
+    }
+    if args.per_file {
+        let limiter = Limiter::new(args.concurrency.into(), Duration::ZERO);
+        return per_file(&repo, &ai_client, &limiter).await;
+    }
+    if let Some(index) = args.stash {
+        let message = ai_client.generate_commit_message(&repo.stash_diff(index)?).await?;
+        println!("stash@{{{}}}: {}", index, format_body(&message, &config.commit));
+        return Ok(());
+    }
+    if let Some(rev) = &args.rev {
+        let message = ai_client.generate_commit_message(&repo.commit_diff(rev)?).await?;
+        println!("{}", format_body(&message, &config.commit));
+        return Ok(());
+    }
+    if args.since_last_tag {
+        return since_last_tag(&repo, &ai_client, &config, args.output.as_deref()).await;
+    }
 
     // Get diff
     info!("Getting git diff");
-    let diff = repo.get_diff()?;
+    let diff = match repo.get_diff() {
+        Err(Error::NoChanges) if args.allow_empty => String::new(),
+        diff => diff?,
+    };
 
     // Show diff if requested
     if config.git.show_diff {
         println!("\nChanges to be committed:\n{}", diff);
     }
 
+    // Leftover conflict markers are a mistake, catch them before spending an API call
+    let conflicted = conflict_marker_files(&diff);
+    if !conflicted.is_empty() {
+        if !args.force {
+            return Err(Error::ConflictMarkers(conflicted));
+        }
+        warn!("Merge conflict markers found in: {}", conflicted.join(", "));
+    }
+
+    // Fail on a bad custom pattern before spending an API call
+    let validator_chain = validators(&config.commit)?;
+
+    // Trivial changes get a local message unless AI is forced
+    let context = CommitContext::from_diff_with_thresholds(&diff, &config.ai.classification);
+    let use_local = !args.force_ai
+        && args.reword.is_none()
+        && context.changed_lines() < config.commit.min_diff_lines;
+    let mut generated_locally = args.message.is_none() && (use_local || args.offline);
+
+    // Sanity check before spending a request, leaving time to Ctrl-C
+    if !args.quiet && args.format == OutputFormat::Text {
+        eprintln!("{}", context.summary());
+    }
+
     // Generate commit message
-    info!("Generating commit message using model {}", config.ai.model);
-    let commit_message = ai_client.generate_commit_message(&diff).await?;
+    if !generated_locally && args.message.is_none() {
+        info!("Generating commit message using model {}", config.ai.model);
+    }
+    let mut validation_retries = 0;
+    let generation = async {
+        if let Some(message) = &args.message {
+            return Ok(message.trim().to_string());
+        }
+        if let Some(previous) = &args.reword {
+            return ai_client.reword_commit_message(&diff, previous).await;
+        }
+        if use_local {
+            info!("Only {} changed lines, skipping the API call", context.changed_lines());
+            return Ok(context.local_message());
+        }
+        if args.offline {
+            return Ok(context.heuristic_message());
+        }
+        // Over the budget, the largest files can be summarized instead of cut off
+        let summarized = if config.ai.hierarchical_summary && count_tokens(&diff) > config.ai.max_diff_tokens {
+            let limiter = Limiter::new(args.concurrency.into(), Duration::ZERO);
+            Some(summarize_large_files(&ai_client, &diff, config.ai.max_diff_tokens, &limiter).await?)
+        } else {
+            None
+        };
+        let (prompt_diff, summaries) = match &summarized {
+            Some((kept, summaries)) => (kept.as_str(), Some(summaries.as_str())),
+            None => (diff.as_str(), None),
+        };
+        // The type and scope come from the whole diff, summarized files included
+        let generated = ai_client.generate_from(&diff, prompt_diff, summaries).await?;
+        validation_retries = generated.validation_retries;
+        let message = generated.message;
+        if !config.commit.enforce_imperative || is_imperative_mood(&message) {
+            return Ok(message);
+        }
+
+        info!("Regenerating, description is not in imperative mood: {}", message);
+        let instruction = format!(
+            "A previous attempt was \"{}\", which does not use imperative mood. \
+            Start the description with an imperative verb such as \"add\", \"fix\" or \"update\".",
+            message
+        );
+        let instruction = match summaries {
+            Some(summaries) => format!("{}\n\n{}", summaries, instruction),
+            None => instruction,
+        };
+        let retry = ai_client.generate_from(&diff, prompt_diff, Some(&instruction)).await?;
+        validation_retries += retry.validation_retries;
+        let retry = retry.message;
+        if !is_imperative_mood(&retry) {
+            warn!("Generated message is still not in imperative mood");
+        }
+        Ok::<_, Error>(retry)
+    };
+
+    // Dropping the generation future on Ctrl-C aborts the in-flight request. Returning
+    // instead of exiting here lets the log file guard flush before `main` exits with 130.
+    let generated = tokio::select! {
+        result = generation => result,
+        _ = tokio::signal::ctrl_c() => {
+            eprintln!("\nInterrupted, no commit was made.");
+            return Err(Error::Cancelled);
+        }
+    };
+
+    // Without network, a heuristic message is better than nothing if the user opted in
+    let generated = match generated {
+        Err(e @ (Error::Request(_) | Error::InvalidResponse(_))) if args.allow_heuristic_fallback => {
+            warn!("API unreachable ({}), using a heuristic message", e);
+            generated_locally = true;
+            Ok(context.heuristic_message())
+        }
+        generated => generated,
+    };
+
+    // Models capitalize descriptions against the spec; a message from --message is kept as is
+    let generated = generated.map(|message| {
+        if config.commit.lowercase_description && args.message.is_none() {
+            normalize_description(&message)
+        } else {
+            message
+        }
+    });
+
+    // Breaking changes get a `BREAKING CHANGE:` footer, asked for if the model marked one
+    let mut breaking_description = args.breaking_description.clone();
+    let generated = generated.and_then(|message| {
+        if breaking_description.is_some() {
+            return Ok(mark_breaking(&message));
+        }
+        let breaking = parse_conventional(&message).is_some_and(|parsed| parsed.breaking);
+        if breaking && breaking_change_footer(&message).is_none()
+            && config.commit.require_confirmation && is_interactive()
+        {
+            print!("\n{}\nThis is marked as a breaking change. Describe what breaks: ", message);
+            io::stdout().flush()?;
+            let mut input = String::new();
+            io::stdin().read_line(&mut input)?;
+            breaking_description = Some(input.trim().to_string());
+        }
+        Ok(message)
+    });
+    let breaking_footer = breaking_description.map(|d| format!("BREAKING CHANGE: {}", d.trim()));
 
     // Verify commit message format if enabled
-    if config.commit.verify_format && !is_conventional_commit(&commit_message) {
-        return Err(Error::CommitMessageGeneration(
-            "Generated message does not follow conventional commit format".to_string(),
-        ));
+    let generated = generated.and_then(|message| {
+        if !config.commit.verify_format {
+            return Ok(message);
+        }
+        let candidate = match &breaking_footer {
+            Some(footer) => format!("{}\n\n{}", message, footer),
+            None => message.clone(),
+        };
+        let candidate = if config.commit.gitmoji { strip_leading_emoji(&candidate) } else { &candidate };
+        let problems = run_validators(&validator_chain, candidate);
+        if problems.is_empty() {
+            Ok(message)
+        } else {
+            warn!("Generated message is invalid: {}", problems.join("; "));
+            Err(Error::ValidationExhausted { last_attempt: message })
+        }
+    });
+
+    // Don't throw away a non-conforming draft: use it unverified, or let the user decide
+    let commit_message = match generated {
+        Err(Error::ValidationExhausted { last_attempt }) if !config.commit.verify_format => last_attempt,
+        Err(Error::ValidationExhausted { last_attempt }) if config.commit.require_confirmation => {
+            offer_draft(last_attempt)?
+        }
+        result => result?,
+    };
+    let commit_message = if args.interactive && is_interactive() && !body_lines(&commit_message).is_empty() {
+        pick_body_lines(&commit_message)?
+    } else {
+        commit_message
+    };
+
+    // Append the user-provided body and any issue footers below the generated subject
+    let mut sections = vec![format_body(&commit_message, &config.commit)];
+    if let Some(body) = body.as_deref().map(str::trim).filter(|b| !b.is_empty()) {
+        sections.push(wrap_body(body, config.commit.body_wrap_width));
+    }
+    let mut footers = Vec::new();
+    if config.commit.detect_issue_keywords {
+        footers.extend(issue_footers(&diff));
+    }
+    footers.extend(breaking_footer);
+    for trailer in trailers {
+        if !has_trailer(&commit_message, &trailer) && !footers.contains(&trailer) {
+            footers.push(trailer);
+        }
+    }
+    let mut commit_message = format!("{}{}", sections.join("\n\n"), assemble_trailers(&footers));
+
+    // Reference the issue key from the branch name, e.g. for Jira Smart Commits
+    let ticket = if config.commit.ticket_from_branch {
+        let branch = repo.current_branch()?.unwrap_or_default();
+        let ticket = ticket_from_branch(&branch, &config.commit.ticket_pattern)?;
+        if ticket.is_none() {
+            warn!("No issue key found in branch name '{}'", branch);
+        }
+        ticket
+    } else {
+        None
+    };
+    if let Some(ticket) = &ticket {
+        commit_message = apply_ticket(&commit_message, ticket, config.commit.ticket_placement);
+    }
+
+    // A long subject is worth a hint, not a regeneration
+    if let Some(warning) = length_warning(&commit_message, &config.commit) {
+        if !args.quiet && args.format == OutputFormat::Text {
+            eprintln!("Warning: {}", warning);
+        }
     }
 
     // Print result
-    println!("\nSuggested commit message:\n{}", commit_message);
+    match args.format {
+        OutputFormat::Text => {
+            let origin = if generated_locally { " (generated locally, not by AI)" } else { "" };
+            println!("\nSuggested commit message{}:\n{}", origin, commit_message);
+            if config.git.show_diff && !generated_locally {
+                eprintln!("Validation retries: {}", validation_retries);
+            }
+        }
+        OutputFormat::Json => {
+            let output = serde_json::json!({
+                "message": commit_message,
+                "diff": diff,
+                "context": context.to_json(),
+                "generated_locally": generated_locally,
+                "validation_retries": validation_retries,
+                "violations": structure_violations(&commit_message)
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>(),
+            });
+            println!("{}", serde_json::to_string_pretty(&output).unwrap_or_default());
+        }
+    }
+
+    // Write the message for a later `git commit` to pick up. The message file is read once
+    // so rewording replaces the suggestion instead of stacking another one on top.
+    let editmsg = match &args.append_to_editmsg {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(existing) => Some((path, existing)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Some((path, String::new())),
+            Err(e) => return Err(e.into()),
+        },
+        None => None,
+    };
+    write_message_files(args.output.as_deref(), editmsg.as_ref(), &commit_message)?;
 
     // Auto-commit if enabled and confirmation is received
     if config.commit.auto_commit {
         if config.commit.require_confirmation {
-            print!("\nDo you want to commit with this message? [y/N] ");
-            io::stdout().flush()?;
-            
-            let mut input = String::new();
-            io::stdin().read_line(&mut input)?;
-            
-            if !matches!(input.trim().to_lowercase().as_str(), "y" | "yes") {
-                println!("Commit aborted.");
-                return Ok(());
+            loop {
+                let choices = if config.commit.confirm_default { "Y/n" } else { "y/N" };
+                print!("\nDo you want to commit with this message? [{}, r to reword] ", choices);
+                io::stdout().flush()?;
+
+                let mut input = String::new();
+                io::stdin().read_line(&mut input)?;
+
+                match parse_confirmation(&input, config.commit.confirm_default) {
+                    Confirmation::Yes => break,
+                    Confirmation::Reword => {
+                        let previous = strip_ticket_prefix(&commit_message, &config.commit.ticket_pattern)?;
+                        commit_message = ai_client.reword_commit_message(&diff, previous).await?;
+                        if let Some(ticket) = &ticket {
+                            commit_message = apply_ticket(&commit_message, ticket, config.commit.ticket_placement);
+                        }
+                        println!("\nSuggested commit message:\n{}", commit_message);
+                        write_message_files(args.output.as_deref(), editmsg.as_ref(), &commit_message)?;
+                    }
+                    Confirmation::No => {
+                        println!("Commit aborted.");
+                        return Ok(());
+                    }
+                }
             }
         }
         
         info!("Auto-committing changes");
         repo.commit(&commit_message)?;
         println!("Changes committed successfully!");
+
+        if args.push {
+            info!("Pushing to upstream");
+            repo.push()?;
+            println!("Pushed to upstream.");
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the message to `--output` and above the original content of `--append-to-editmsg`
+fn write_message_files(output: Option<&Path>, editmsg: Option<&(&PathBuf, String)>, message: &str) -> Result<()> {
+    if let Some(output) = output {
+        std::fs::write(output, format!("{}\n", message))?;
+        info!("Commit message written to {}", output.display());
+    }
+    if let Some((path, existing)) = editmsg {
+        std::fs::write(path, prepend_to_editmsg(existing, message))?;
+        info!("Commit message inserted into {}", path.display());
+    }
+    Ok(())
+}
+
+/// Whether a user can answer prompts: both ends are terminals and this isn't CI
+fn is_interactive() -> bool {
+    io::stdin().is_terminal() && io::stdout().is_terminal() && std::env::var_os("CI").is_none()
+}
+
+/// Asks which model to use on first run; an empty or invalid answer picks the recommended one
+fn choose_model() -> Result<String> {
+    println!("No model configured yet. Available models:");
+    for (i, (model, description, _)) in AVAILABLE_MODELS.iter().enumerate() {
+        println!("  {}. {} - {}", i + 1, model, description);
+    }
+    print!("Choose a model [1]: ");
+    io::stdout().flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    let choice = match input.trim() {
+        "" => 0,
+        answer => match answer.parse::<usize>() {
+            Ok(n) if (1..=AVAILABLE_MODELS.len()).contains(&n) => n - 1,
+            _ => {
+                warn!("Invalid choice '{}', using the recommended model", answer);
+                0
+            }
+        },
+    };
+    Ok(AVAILABLE_MODELS[choice].0.to_string())
+}
+
+/// Shows a draft that failed validation and asks whether to use, edit or drop it
+fn offer_draft(last_attempt: String) -> Result<String> {
+    warn!("Could not generate a conventional commit message");
+    println!("\nBest attempt:\n{}", last_attempt);
+    print!("\nUse it anyway [u], edit it [e], or abort [A]? ");
+    io::stdout().flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    match input.trim().to_lowercase().as_str() {
+        "u" | "use" => Ok(last_attempt),
+        "e" | "edit" => {
+            print!("New message (empty keeps the draft): ");
+            io::stdout().flush()?;
+            let mut edited = String::new();
+            io::stdin().read_line(&mut edited)?;
+            match edited.trim() {
+                "" => Ok(last_attempt),
+                edited => Ok(edited.to_string()),
+            }
+        }
+        _ => Err(Error::ValidationExhausted { last_attempt }),
     }
+}
+
+/// Lists the body lines of a message with `[x]`/`[ ]` toggles until the user accepts the
+/// selection, and returns the message with the kept lines (wrapped later by `format_body`)
+fn pick_body_lines(message: &str) -> Result<String> {
+    let lines = body_lines(message);
+    let mut keep = vec![true; lines.len()];
+    loop {
+        println!("\n{}\n", message.lines().next().unwrap_or(""));
+        for (i, (line, kept)) in lines.iter().zip(&keep).enumerate() {
+            println!("  {}. [{}] {}", i + 1, if *kept { 'x' } else { ' ' }, line.trim());
+        }
+        print!("\nToggle lines by number (e.g. 2 4), Enter to accept: ");
+        io::stdout().flush()?;
+
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        if input.trim().is_empty() {
+            return Ok(select_body_lines(message, &keep));
+        }
+        toggle_lines(&mut keep, &input);
+    }
+}
+
+/// Runs health checks against the repository and provider, reporting each one
+async fn doctor(config: &Config, api_key: Option<String>) -> Result<()> {
+    let mut failures = 0;
+    let mut report = |check: &str, outcome: std::result::Result<String, String>| match outcome {
+        Ok(detail) => println!("✓ {}: {}", check, detail),
+        Err(reason) => {
+            failures += 1;
+            println!("✗ {}: {}", check, reason);
+        }
+    };
 
+    report(
+        "Repository",
+        GitRepo::new(config.git.clone())
+            .map(|_| format!("opened {}", config.git.repo_path.display()))
+            .map_err(|e| e.to_string()),
+    );
+
+    let api_key = api_key.or_else(|| std::env::var(config.ai.provider.api_key_env()).ok());
+    report(
+        "API key",
+        api_key.as_ref().map(|_| "set".to_string()).ok_or_else(|| Error::NoApiKey.to_string()),
+    );
+
+    if let Some(api_key) = api_key {
+        let (model, headers) = (config.ai.model.clone(), config.ai.extra_headers.clone());
+        let ping = ModelContext {
+            messages: vec![Message {
+                role: "user".to_string(),
+                content: "Reply with OK.".to_string(),
+            }],
+            config: GenerationConfig {
+                temperature: 0.0,
+                max_tokens: 1,
+                stop_sequences: Vec::new(),
+            },
+        };
+        let outcome = match config.ai.provider {
+            Provider::Together => TogetherAiProvider::new(api_key, model)
+                .with_headers(headers)
+                .with_base_url(config.ai.api_base_url.clone())
+                .generate(ping)
+                .await,
+            Provider::HuggingFace => HuggingFaceProvider::new(api_key, model)
+                .with_headers(headers)
+                .generate(ping)
+                .await,
+        };
+        report(
+            "Provider",
+            outcome
+                .map(|_| format!("{} responded for model {}", config.ai.provider, config.ai.model))
+                .map_err(|e| e.to_string()),
+        );
+    }
+
+    if failures > 0 {
+        return Err(Error::HealthCheck(failures));
+    }
     Ok(())
 }
 
-fn setup_logging(debug: bool) {
+/// Rewrites a free-form message as a conventional subject, keeping any body as written
+async fn convert(message: &str, config: &Config, api_key: Option<String>) -> Result<()> {
+    let api_key = api_key
+        .or_else(|| std::env::var(config.ai.provider.api_key_env()).ok())
+        .ok_or(Error::NoApiKey)?;
+    let (model, headers) = (config.ai.model.clone(), config.ai.extra_headers.clone());
+    let context = conversion_context(
+        message,
+        &config.commit.allowed_types,
+        GenerationConfig {
+            temperature: config.ai.temperature,
+            max_tokens: config.ai.max_tokens,
+            stop_sequences: config.ai.stop_sequences.clone(),
+        },
+    );
+    let subject = match config.ai.provider {
+        Provider::Together => TogetherAiProvider::new(api_key, model)
+            .with_headers(headers)
+            .with_base_url(config.ai.api_base_url.clone())
+            .generate(context)
+            .await?,
+        Provider::HuggingFace => HuggingFaceProvider::new(api_key, model)
+            .with_headers(headers)
+            .generate(context)
+            .await?,
+    };
+    let subject = subject.lines().next().unwrap_or_default().trim().to_string();
+    if !is_conventional_commit_with_types(&subject, &config.commit.allowed_types) {
+        return Err(Error::CommitMessageGeneration(format!(
+            "the model did not return a conventional message: {}",
+            subject
+        )));
+    }
+
+    match message.trim().split_once('\n') {
+        Some((_, body)) if !body.trim().is_empty() => println!("{}\n\n{}", subject, body.trim()),
+        _ => println!("{}", subject),
+    }
+    Ok(())
+}
+
+/// Validates a single message, for use in commit-msg hooks
+fn check_message(message: &str, config: &Config) -> Result<()> {
+    let problems = validate_message(message, &config.commit)?;
+    if !problems.is_empty() {
+        return Err(Error::InvalidMessage(problems));
+    }
+    println!("Commit message is valid.");
+    Ok(())
+}
+
+/// Parses a `KEY=VALUE` header argument, validating both parts
+fn parse_header(arg: &str) -> std::result::Result<(String, String), String> {
+    let (name, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{}'", arg))?;
+    reqwest::header::HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("invalid header name '{}'", name))?;
+    reqwest::header::HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("invalid header value for '{}'", name))?;
+    Ok((name.trim().to_string(), value.trim().to_string()))
+}
+
+/// Parses a `KEY=VALUE` request parameter; values that aren't valid JSON, like `abc`,
+/// are taken as strings
+fn parse_param(arg: &str) -> std::result::Result<(String, serde_json::Value), String> {
+    let (key, value) = arg
+        .split_once('=')
+        .filter(|(key, _)| !key.trim().is_empty())
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{}'", arg))?;
+    let value = value.trim();
+    let value = serde_json::from_str(value).unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+    Ok((key.trim().to_string(), value))
+}
+
+/// Bounds the API requests of bulk modes: at most `concurrency` in flight, started at
+/// least `min_interval` apart. Both limits apply, so a request may wait for a free slot
+/// and then for the interval.
+struct Limiter {
+    slots: tokio::sync::Semaphore,
+    min_interval: Duration,
+    next_start: tokio::sync::Mutex<tokio::time::Instant>,
+}
+
+impl Limiter {
+    fn new(concurrency: usize, min_interval: Duration) -> Self {
+        Self {
+            slots: tokio::sync::Semaphore::new(concurrency),
+            min_interval,
+            next_start: tokio::sync::Mutex::new(tokio::time::Instant::now()),
+        }
+    }
+
+    /// Waits for a free slot and the interval since the previous start; the slot is held
+    /// until the returned permit is dropped
+    async fn acquire(&self) -> tokio::sync::SemaphorePermit<'_> {
+        let permit = self.slots.acquire().await.expect("the semaphore is never closed");
+        let mut next_start = self.next_start.lock().await;
+        tokio::time::sleep_until(*next_start).await;
+        *next_start = tokio::time::Instant::now() + self.min_interval;
+        permit
+    }
+}
+
+/// Groups the changed files by area and proposes a commit message for each group
+async fn split(repo: &GitRepo, ai_client: &AiClient, args: SplitArgs, limiter: &Limiter) -> Result<()> {
+    let diff = repo.get_working_tree_diff()?;
+    let groups = group_by_area(split_by_file(&diff));
+
+    let plan = futures::future::try_join_all(groups.into_iter().map(|(area, files)| async move {
+        let _slot = limiter.acquire().await;
+        info!("Generating commit message for {} ({} files)", area, files.len());
+        let group_diff: String = files.iter().map(|file| file.diff.as_str()).collect();
+        let message = ai_client.generate_commit_message(&group_diff).await?;
+        let paths: Vec<String> = files.into_iter().map(|file| file.path).collect();
+        Ok::<_, Error>((area, paths, message))
+    }))
+    .await?;
+
+    if args.script {
+        println!("#!/bin/sh\nset -e\n# Start from an empty index so each commit only gets its own files\ngit reset -q");
+        for (_, paths, message) in &plan {
+            let paths: Vec<String> = paths.iter().map(|p| shell_quote(p)).collect();
+            println!("git add -A -- {} && git commit -m {}", paths.join(" "), shell_quote(message));
+        }
+        return Ok(());
+    }
+
+    println!("Suggested commits:");
+    for (i, (area, paths, message)) in plan.iter().enumerate() {
+        println!("\n{}. {} ({})", i + 1, message, area);
+        for path in paths {
+            println!("   {}", path);
+        }
+    }
+    Ok(())
+}
+
+/// Summarizes the largest files of a diff over `budget` tokens in a sentence each, with
+/// requests bounded by `limiter`. Returns the diffs of the other files and an instruction
+/// listing the summaries for the prompt.
+async fn summarize_large_files(
+    ai_client: &AiClient,
+    diff: &str,
+    budget: usize,
+    limiter: &Limiter,
+) -> Result<(String, String)> {
+    let (large, kept) = split_for_summary(split_by_file(diff), budget);
+    info!("Diff is over {} tokens, summarizing {} large files first", budget, large.len());
+    let summaries = futures::future::try_join_all(large.into_iter().map(|file| async move {
+        let _slot = limiter.acquire().await;
+        let summary = ai_client.summarize_file_diff(&file.path, &file.diff).await?;
+        Ok::<_, Error>(format!("- {}: {}", file.path, summary))
+    }))
+    .await?;
+    let kept: String = kept.into_iter().map(|file| file.diff).collect();
+    let instruction = format!(
+        "These files were too large for the diff above and are summarized instead:\n{}",
+        summaries.join("\n")
+    );
+    Ok((kept, instruction))
+}
+
+/// Prints a suggested message for each changed file, with requests bounded by `limiter`
+async fn per_file(repo: &GitRepo, ai_client: &AiClient, limiter: &Limiter) -> Result<()> {
+    let diff = repo.get_working_tree_diff()?;
+    let files = split_by_file(&diff);
+    let buffer = files.len().max(1);
+    let mut suggestions = futures::stream::iter(files)
+        .map(|file| async move {
+            let _slot = limiter.acquire().await;
+            let message = ai_client.generate_commit_message(&file.diff).await;
+            (file.path, message)
+        })
+        .buffered(buffer);
+
+    // Print in file order as results arrive, without failing the whole run on one file
+    let mut failures = 0;
+    while let Some((path, message)) = suggestions.next().await {
+        match message {
+            Ok(message) => println!("{}: {}", path, message.lines().next().unwrap_or("")),
+            Err(e) => {
+                failures += 1;
+                eprintln!("{}: error: {}", path, e);
+            }
+        }
+    }
+    if failures > 0 {
+        return Err(Error::CommitMessageGeneration(format!("{} file(s) failed", failures)));
+    }
+    Ok(())
+}
+
+/// Generates a message for the current diff with each model in turn, then prints the
+/// suggestions with their latency as a table or JSON
+async fn bench(
+    repo: &GitRepo,
+    ai_client: &AiClient,
+    args: BenchArgs,
+    limiter: &Limiter,
+    format: OutputFormat,
+) -> Result<()> {
+    let diff = repo.get_diff()?;
+
+    // The limiter spaces out the calls so a long model list doesn't trip the provider's limits
+    let diff = &diff;
+    let results = futures::future::join_all(args.models.into_iter().map(|model| async move {
+        let ai_client = ai_client.for_model(model.clone());
+        let _slot = limiter.acquire().await;
+        info!("Generating with {}", model);
+        let started = Instant::now();
+        let message = ai_client.generate_commit_message(diff).await;
+        (model, started.elapsed(), message)
+    }))
+    .await;
+
+    match format {
+        OutputFormat::Text => {
+            let width = results.iter().map(|(model, _, _)| model.len()).max().unwrap_or(0).max(5);
+            println!("{:<width$}  {:>8}  MESSAGE", "MODEL", "LATENCY", width = width);
+            for (model, latency, message) in &results {
+                let message = match message {
+                    Ok(message) => message.lines().next().unwrap_or("").to_string(),
+                    Err(e) => format!("error: {}", e),
+                };
+                let latency = format!("{:.2}s", latency.as_secs_f64());
+                println!("{:<width$}  {:>8}  {}", model, latency, message, width = width);
+            }
+        }
+        OutputFormat::Json => {
+            let output: Vec<_> = results
+                .iter()
+                .map(|(model, latency, message)| {
+                    serde_json::json!({
+                        "model": model,
+                        "latency_ms": latency.as_millis() as u64,
+                        "message": message.as_ref().ok(),
+                        "error": message.as_ref().err().map(|e| e.to_string()),
+                    })
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&output).unwrap_or_default());
+        }
+    }
+    Ok(())
+}
+
+/// Summarizes the changes since the most recent tag as a subject and a bullet-point body.
+/// The subject length limit doesn't apply, since the result goes into a tag annotation.
+async fn since_last_tag(repo: &GitRepo, ai_client: &AiClient, config: &Config, output: Option<&Path>) -> Result<()> {
+    let (tag, diff) = repo.diff_since_last_tag()?.ok_or(Error::NoTag)?;
+    info!("Summarizing the changes since {}", tag);
+    let instruction = format!(
+        "The diff covers every change since the {} release. Sum up the release in the \
+        subject line, and list its notable changes in the body.",
+        tag
+    );
+    let message = ai_client
+        .generate_commit_message_with_instruction(&diff, &instruction)
+        .await?;
+    let message = format_body(&message, &config.commit);
+    println!("\nChanges since {}:\n{}", tag, message);
+    if let Some(output) = output {
+        std::fs::write(output, format!("{}\n", message))?;
+        info!("Summary written to {}", output.display());
+    }
+    Ok(())
+}
+
+/// Quotes a string for POSIX shells
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Regenerates and prints a suggestion whenever the working tree changes
+async fn watch(repo: &GitRepo, ai_client: &AiClient, config: &Config, args: WatchArgs) -> Result<()> {
+    let debounce = Duration::from_millis(args.debounce_ms);
+    let min_interval = Duration::from_secs(args.min_interval);
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            let _ = tx.send(event);
+        }
+    })?;
+    let root = std::fs::canonicalize(&config.git.repo_path)?;
+    watcher.watch(&root, RecursiveMode::Recursive)?;
+    println!("Watching {} for changes (Ctrl-C to stop)...", root.display());
+
+    let mut last_run: Option<Instant> = None;
+    loop {
+        // Wait for a change to a tracked or untracked (but not ignored) file
+        let event = tokio::select! {
+            event = rx.recv() => event,
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        };
+        let Some(event) = event else {
+            return Ok(());
+        };
+        if event.paths.iter().all(|path| repo.is_ignored(path)) {
+            continue;
+        }
+
+        // Debounce bursts of events, e.g. editors writing several files on save
+        while let Ok(event) = tokio::time::timeout(debounce, rx.recv()).await {
+            if event.is_none() {
+                return Ok(());
+            }
+        }
+
+        // Rate limit API calls so rapid saves don't spam the provider
+        if let Some(elapsed) = last_run.map(|t| t.elapsed()) {
+            if elapsed < min_interval {
+                tokio::time::sleep(min_interval - elapsed).await;
+                while rx.try_recv().is_ok() {}
+            }
+        }
+        last_run = Some(Instant::now());
+
+        let diff = match repo.get_diff() {
+            Ok(diff) => diff,
+            Err(Error::NoChanges) => {
+                println!("\nNo changes to describe.");
+                continue;
+            }
+            Err(e @ Error::AllChangesFiltered(_)) => {
+                println!("\n{}", e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        match ai_client.generate_commit_message(&diff).await {
+            Ok(message) => println!("\nSuggested commit message:\n{}", message),
+            Err(e) => warn!("Failed to generate commit message: {}", e),
+        }
+    }
+}
+
+/// Sets up console logging and, if requested, debug-level logging to a file.
+/// The returned guard flushes the file writer when dropped.
+fn setup_logging(debug: bool, log_file: Option<&Path>) -> Result<Option<WorkerGuard>> {
     let filter = if debug { "debug" } else { "info" };
-    
-    tracing_subscriber::fmt()
-        .with_env_filter(filter)
+
+    let console = tracing_subscriber::fmt::layer()
         .with_target(false)
         .with_thread_ids(false)
         .with_thread_names(false)
         .with_file(false)
         .with_line_number(false)
-        .init();
+        .with_filter(EnvFilter::new(filter));
+
+    let (file, guard) = match log_file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+            let (writer, guard) = tracing_appender::non_blocking(file);
+            let layer = tracing_subscriber::fmt::layer()
+                .with_writer(writer)
+                .with_ansi(false)
+                .with_filter(EnvFilter::new("info,git_commit_sage=debug"));
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    tracing_subscriber::registry().with(console).with(file).init();
+    Ok(guard)
 }
\ No newline at end of file
@@ -1,6 +1,16 @@
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::time::Duration;
+
+/// Default base URL of the Together.ai API, see `AiConfig::api_base_url`
+pub const TOGETHER_API_BASE_URL: &str = "https://api.together.xyz/v1";
+const HUGGINGFACE_API_URL: &str = "https://api-inference.huggingface.co/models";
+/// How many times to wait for a Hugging Face model that is still loading
+const HUGGINGFACE_LOADING_RETRIES: u32 = 5;
+/// Upper bound on a single wait for a loading model, in seconds
+const HUGGINGFACE_MAX_WAIT_SECS: f64 = 60.0;
 
 /// Represents a message in a conversation
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -17,6 +27,26 @@ pub struct GenerationConfig {
     pub stop_sequences: Vec<String>,
 }
 
+/// What a provider supports, so callers can refuse unsupported features up front
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProviderCapabilities {
+    /// Whether responses can be streamed token by token
+    pub streaming: bool,
+    /// Whether `response_format: {"type": "json_object"}` is honored
+    pub json_mode: bool,
+    /// Context window of the model, in tokens
+    pub max_context_tokens: usize,
+}
+
+impl ProviderCapabilities {
+    /// Assumes nothing beyond plain chat completions and a small context window
+    pub const CONSERVATIVE: Self = Self {
+        streaming: false,
+        json_mode: false,
+        max_context_tokens: 4096,
+    };
+}
+
 /// Context for model interaction
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelContext {
@@ -38,6 +68,16 @@ pub trait ModelProvider: Send + Sync {
 
     /// Get the default configuration for this model
     fn default_config(&self) -> GenerationConfig;
+
+    /// Features supported by the provider, conservative unless the provider knows better
+    fn capabilities(&self) -> ProviderCapabilities {
+        ProviderCapabilities::CONSERVATIVE
+    }
+
+    /// Commit types generated messages may use, the Conventional Commits ones by default
+    fn allowed_types(&self) -> Vec<String> {
+        crate::CommitConfig::default().allowed_types
+    }
 }
 
 /// Trait for commit message generators
@@ -63,15 +103,18 @@ impl<T: ModelProvider> CommitMessageGenerator for T {
             messages: vec![
                 Message {
                     role: "system".to_string(),
-                    content: "You are a highly skilled developer who writes perfect conventional commit messages. \
+                    content: format!(
+                        "You are a highly skilled developer who writes perfect conventional commit messages. \
                         You analyze git diffs and generate commit messages following the Conventional Commits specification. \
                         Your messages should be descriptive and precise, following this format:\n\
                         - For small changes: type(scope): concise description\n\
                         - For large changes (>5 files or >100 lines): type(scope): comprehensive description of main changes\n\
-                        The type must be one of: feat, fix, docs, style, refactor, perf, test, build, ci, chore, revert.\n\
+                        The type must be one of: {}.\n\
                         The scope should reflect the main component being changed.\n\
                         The description should be clear, precise, and written in imperative mood.\n\
-                        For large changes, ensure the description captures the major components being modified.".to_string(),
+                        For large changes, ensure the description captures the major components being modified.",
+                        self.allowed_types().join(", ")
+                    ),
                 },
                 Message {
                     role: "user".to_string(),
@@ -92,25 +135,36 @@ impl<T: ModelProvider> CommitMessageGenerator for T {
     }
 
     fn validate_message(&self, message: &str) -> bool {
-        // Basic format: <type>[optional scope]: <description>
-        let parts: Vec<&str> = message.splitn(2, ": ").collect();
-        if parts.len() != 2 {
-            return false;
-        }
-
-        let type_part = parts[0];
-        let commit_type = if type_part.contains('(') {
-            type_part.split('(').next().unwrap_or("")
-        } else {
-            type_part
-        };
-
-        let valid_types = [
-            "feat", "fix", "docs", "style", "refactor",
-            "perf", "test", "build", "ci", "chore", "revert"
-        ];
+        crate::is_conventional_commit_with_types(message, &self.allowed_types())
+    }
+}
 
-        valid_types.contains(&commit_type)
+/// Context asking a model to rewrite a free-form commit message, such as one from legacy
+/// history, as a conventional commit subject
+pub fn conversion_context(message: &str, allowed_types: &[String], config: GenerationConfig) -> ModelContext {
+    ModelContext {
+        messages: vec![
+            Message {
+                role: "system".to_string(),
+                content: "You rewrite commit messages into the Conventional Commits format: \
+                    type(scope): description. Infer the type and, when the message names a \
+                    component, the scope from the text alone. Write the description in \
+                    imperative mood and keep its meaning; never add details that are not in \
+                    the original message.".to_string(),
+            },
+            Message {
+                role: "user".to_string(),
+                content: format!(
+                    "Rewrite this commit message as a single conventional commit subject line.\n\
+                    The type must be one of: {}.\n\
+                    Only return the subject line, nothing else.\n\n\
+                    Message:\n{}",
+                    allowed_types.join(", "),
+                    message.trim()
+                ),
+            },
+        ],
+        config,
     }
 }
 
@@ -119,6 +173,9 @@ pub struct TogetherAiProvider {
     api_key: String,
     model: String,
     client: reqwest::Client,
+    extra_headers: HashMap<String, String>,
+    allowed_types: Vec<String>,
+    base_url: String,
 }
 
 #[async_trait]
@@ -135,18 +192,20 @@ impl ModelProvider for TogetherAiProvider {
         });
 
         let response = self.client
-            .post("https://api.together.xyz/v1/chat/completions")
+            .post(self.chat_url())
             .header("Authorization", format!("Bearer {}", self.api_key))
+            .headers(crate::ai::build_headers(&self.extra_headers)?)
             .json(&request)
             .send()
+            .await?;
+        let response = crate::ai::error_for_status(response)
             .await?
-            .error_for_status()?
             .json::<serde_json::Value>()
             .await?;
 
         response["choices"][0]["message"]["content"]
             .as_str()
-            .map(|s| s.trim().to_string())
+            .map(|content| crate::ai::normalize_message(content, false))
             .ok_or_else(|| crate::Error::CommitMessageGeneration("No response from API".to_string()))
     }
 
@@ -154,6 +213,20 @@ impl ModelProvider for TogetherAiProvider {
         &self.model
     }
 
+    fn allowed_types(&self) -> Vec<String> {
+        self.allowed_types.clone()
+    }
+
+    fn capabilities(&self) -> ProviderCapabilities {
+        // Llama 2 models are served with their original 4k window, the others with 32k
+        let max_context_tokens = if self.model.to_lowercase().contains("llama-2") { 4096 } else { 32_768 };
+        ProviderCapabilities {
+            streaming: true,
+            json_mode: true,
+            max_context_tokens,
+        }
+    }
+
     fn default_config(&self) -> GenerationConfig {
         GenerationConfig {
             temperature: 0.3,
@@ -169,6 +242,146 @@ impl TogetherAiProvider {
             api_key,
             model,
             client: reqwest::Client::new(),
+            extra_headers: HashMap::new(),
+            allowed_types: crate::CommitConfig::default().allowed_types,
+            base_url: TOGETHER_API_BASE_URL.to_string(),
         }
     }
-} 
\ No newline at end of file
+
+    /// Sends the given extra HTTP headers with every request
+    pub fn with_headers(mut self, headers: HashMap<String, String>) -> Self {
+        self.extra_headers = headers;
+        self
+    }
+
+    /// Restricts generated messages to the given commit types, e.g. `CommitConfig::allowed_types`
+    pub fn with_allowed_types(mut self, allowed_types: Vec<String>) -> Self {
+        self.allowed_types = allowed_types;
+        self
+    }
+
+    /// Sends requests to another base URL than `TOGETHER_API_BASE_URL`, e.g. a proxy
+    pub fn with_base_url(mut self, base_url: String) -> Self {
+        self.base_url = base_url;
+        self
+    }
+
+    pub(crate) fn chat_url(&self) -> String {
+        together_url(&self.base_url, "chat/completions")
+    }
+}
+
+/// Joins a Together.ai base URL and an endpoint path, with or without a trailing slash
+pub(crate) fn together_url(base_url: &str, path: &str) -> String {
+    format!("{}/{}", base_url.trim_end_matches('/'), path)
+}
+
+/// Hugging Face Inference API implementation of ModelProvider
+pub struct HuggingFaceProvider {
+    api_key: String,
+    model: String,
+    client: reqwest::Client,
+    extra_headers: HashMap<String, String>,
+    allowed_types: Vec<String>,
+}
+
+#[async_trait]
+impl ModelProvider for HuggingFaceProvider {
+    type Error = crate::Error;
+
+    async fn generate(&self, context: ModelContext) -> Result<String, Self::Error> {
+        let request = serde_json::json!({
+            "model": self.model,
+            "messages": context.messages,
+            "temperature": context.config.temperature,
+            "max_tokens": context.config.max_tokens,
+            "stop": context.config.stop_sequences,
+        });
+
+        let mut attempt = 0;
+        let response = loop {
+            let response = self.client
+                .post(Self::chat_url(&self.model))
+                .header("Authorization", format!("Bearer {}", self.api_key))
+                .header("x-wait-for-model", "true")
+                .headers(crate::ai::build_headers(&self.extra_headers)?)
+                .json(&request)
+                .send()
+                .await?;
+
+            // Cold models answer 503 with an estimate of how long loading takes
+            if response.status() == reqwest::StatusCode::SERVICE_UNAVAILABLE
+                && attempt < HUGGINGFACE_LOADING_RETRIES
+            {
+                attempt += 1;
+                let wait = response
+                    .json::<serde_json::Value>()
+                    .await
+                    .ok()
+                    .and_then(|body| body["estimated_time"].as_f64())
+                    .unwrap_or(10.0)
+                    .clamp(1.0, HUGGINGFACE_MAX_WAIT_SECS);
+                tracing::info!("Model {} is loading, retrying in {:.0}s", self.model, wait);
+                tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+                continue;
+            }
+
+            break crate::ai::error_for_status(response).await?.json::<serde_json::Value>().await?;
+        };
+
+        response["choices"][0]["message"]["content"]
+            .as_str()
+            .map(|content| crate::ai::normalize_message(content, false))
+            .ok_or_else(|| crate::Error::CommitMessageGeneration("No response from API".to_string()))
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model
+    }
+
+    fn allowed_types(&self) -> Vec<String> {
+        self.allowed_types.clone()
+    }
+
+    fn default_config(&self) -> GenerationConfig {
+        GenerationConfig {
+            temperature: 0.3,
+            max_tokens: 150,
+            stop_sequences: vec!["\n".to_string()],
+        }
+    }
+}
+
+impl HuggingFaceProvider {
+    pub fn new(api_key: String, model: String) -> Self {
+        Self {
+            api_key,
+            model,
+            client: reqwest::Client::new(),
+            extra_headers: HashMap::new(),
+            allowed_types: crate::CommitConfig::default().allowed_types,
+        }
+    }
+
+    /// Sends the given extra HTTP headers with every request
+    pub fn with_headers(mut self, headers: HashMap<String, String>) -> Self {
+        self.extra_headers = headers;
+        self
+    }
+
+    /// Restricts generated messages to the given commit types, e.g. `CommitConfig::allowed_types`
+    pub fn with_allowed_types(mut self, allowed_types: Vec<String>) -> Self {
+        self.allowed_types = allowed_types;
+        self
+    }
+
+    /// OpenAI-compatible chat completions endpoint for a model repository
+    pub fn chat_url(model: &str) -> String {
+        format!("{}/{}/v1/chat/completions", HUGGINGFACE_API_URL, model)
+    }
+
+    /// OpenAI-compatible completions endpoint for a model repository
+    pub fn completions_url(model: &str) -> String {
+        format!("{}/{}/v1/completions", HUGGINGFACE_API_URL, model)
+    }
+}
@@ -0,0 +1,60 @@
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::{Error, Result};
+
+/// One API call: the exact request body sent and the response received
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exchange {
+    pub url: String,
+    pub request: serde_json::Value,
+    pub response: serde_json::Value,
+}
+
+/// Reads exchanges written by [`save`], in the order they happened
+pub fn load(path: &Path) -> Result<Vec<Exchange>> {
+    serde_json::from_str(&std::fs::read_to_string(path)?)
+        .map_err(|e| Error::Replay(format!("{} is not a recording: {}", path.display(), e)))
+}
+
+/// Writes exchanges as a pretty-printed JSON array, replacing the file
+pub fn save(path: &Path, exchanges: &[Exchange]) -> Result<()> {
+    let json = serde_json::to_string_pretty(exchanges)
+        .map_err(|e| Error::Recording(format!("cannot serialize the recording: {}", e)))?;
+    std::fs::write(path, json + "\n")
+        .map_err(|e| Error::Recording(format!("cannot write {}: {}", path.display(), e)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_save_and_load_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        let exchanges = vec![Exchange {
+            url: "https://api.together.xyz/v1/chat/completions".to_string(),
+            request: serde_json::json!({ "model": "m", "temperature": 0.3 }),
+            response: serde_json::json!({ "choices": [{ "message": { "content": "feat: add x" } }] }),
+        }];
+        save(&path, &exchanges).unwrap();
+        assert_eq!(load(&path).unwrap(), exchanges);
+    }
+
+    #[test]
+    fn test_load_invalid_recording_is_a_replay_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        std::fs::write(&path, "not json").unwrap();
+        assert!(matches!(load(&path), Err(Error::Replay(_))));
+    }
+
+    #[test]
+    fn test_save_failure_is_a_recording_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing").join("session.json");
+        assert!(matches!(save(&path, &[]), Err(Error::Recording(_))));
+    }
+}
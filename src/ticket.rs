@@ -0,0 +1,62 @@
+use regex::Regex;
+use crate::{Result, TicketPlacement};
+
+/// Default pattern for issue keys such as Jira's `PROJ-123`
+pub const DEFAULT_TICKET_PATTERN: &str = r"[A-Z][A-Z0-9]+-\d+";
+
+/// Extracts the first issue key matching `pattern` from a branch name,
+/// e.g. `PROJ-123` from `feature/PROJ-123-login`
+pub fn ticket_from_branch(branch: &str, pattern: &str) -> Result<Option<String>> {
+    let regex = Regex::new(pattern)?;
+    Ok(regex.find(branch).map(|m| m.as_str().to_string()))
+}
+
+/// Adds the issue key to a message, as `PROJ-123 feat: ...` or as a footer line.
+/// Messages already mentioning the key are left unchanged.
+pub fn apply_ticket(message: &str, ticket: &str, placement: TicketPlacement) -> String {
+    if message.contains(ticket) {
+        return message.to_string();
+    }
+    match placement {
+        TicketPlacement::Prefix => format!("{} {}", ticket, message),
+        TicketPlacement::Footer => format!("{}\n\n{}", message.trim_end(), ticket),
+    }
+}
+
+/// Strips a leading issue key so the rest of the message can be validated on its own
+pub fn strip_ticket_prefix<'a>(message: &'a str, pattern: &str) -> Result<&'a str> {
+    let regex = Regex::new(&format!(r"^(?:{})\s+", pattern))?;
+    Ok(match regex.find(message) {
+        Some(key) => &message[key.end()..],
+        None => message,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    #[test_case("feature/PROJ-123-login", Some("PROJ-123"); "feature branch")]
+    #[test_case("main", None; "no ticket")]
+    fn test_ticket_from_branch(branch: &str, expected: Option<&str>) {
+        assert_eq!(
+            ticket_from_branch(branch, DEFAULT_TICKET_PATTERN).unwrap().as_deref(),
+            expected
+        );
+    }
+
+    #[test_case(TicketPlacement::Prefix, "PROJ-123 feat: add login"; "prefix")]
+    #[test_case(TicketPlacement::Footer, "feat: add login\n\nPROJ-123"; "footer")]
+    fn test_apply_ticket(placement: TicketPlacement, expected: &str) {
+        assert_eq!(apply_ticket("feat: add login", "PROJ-123", placement), expected);
+    }
+
+    #[test]
+    fn test_prefixed_message_validates_after_the_key() {
+        let message = apply_ticket("feat: add login", "PROJ-123", TicketPlacement::Prefix);
+        let rest = strip_ticket_prefix(&message, DEFAULT_TICKET_PATTERN).unwrap();
+        assert!(crate::is_conventional_commit(rest));
+    }
+}
@@ -0,0 +1,132 @@
+use crate::{Error, Result};
+
+/// Parses trailers kept in a file, one `Token: value` per line. Blank lines and `#`
+/// comments are skipped; tokens are letters, digits and `-`, as `git interpret-trailers`
+/// expects.
+pub fn parse_trailers(source: &str) -> Result<Vec<String>> {
+    let mut trailers = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || Error::InvalidTrailer { line: index + 1, text: line.to_string() };
+        let (token, value) = split_trailer(line).ok_or_else(invalid)?;
+        trailers.push(format!("{}: {}", token, value));
+    }
+    Ok(trailers)
+}
+
+/// Whether the message already has the trailer, comparing tokens case-insensitively
+pub fn has_trailer(message: &str, trailer: &str) -> bool {
+    let Some((token, value)) = split_trailer(trailer) else {
+        return false;
+    };
+    message.lines().filter_map(|line| split_trailer(line.trim())).any(|(t, v)| {
+        t.eq_ignore_ascii_case(token) && v == value
+    })
+}
+
+/// Tokens of trailers that reference issues, like `Refs: #12` (besides `Closes #12`)
+const REF_TOKENS: [&str; 6] = ["refs", "ref", "closes", "fixes", "resolves", "see-also"];
+
+/// Position of a trailer in the canonical order: issue references, then other trailers
+/// (such as `BREAKING CHANGE` or `Reviewed-by`), then `Co-authored-by`, then `Signed-off-by`
+fn trailer_rank(trailer: &str) -> u8 {
+    let Some((token, _)) = split_trailer(trailer) else {
+        // `Closes #12` style references; `BREAKING CHANGE` has a space in its token
+        return if trailer.contains(" #") { 0 } else { 1 };
+    };
+    match token.to_ascii_lowercase().as_str() {
+        token if REF_TOKENS.contains(&token) => 0,
+        "co-authored-by" => 2,
+        "signed-off-by" => 3,
+        _ => 1,
+    }
+}
+
+/// The footer block for a set of trailers in canonical order (see `trailer_rank`), keeping
+/// the given order within each group. It starts with the single blank line separating it
+/// from the body, so it is appended as is; no trailers give an empty string.
+pub fn assemble_trailers(parts: &[String]) -> String {
+    let mut trailers: Vec<&str> = parts.iter().map(|part| part.trim()).filter(|part| !part.is_empty()).collect();
+    if trailers.is_empty() {
+        return String::new();
+    }
+    trailers.sort_by_key(|trailer| trailer_rank(trailer));
+    format!("\n\n{}", trailers.join("\n"))
+}
+
+/// Splits a `Token: value` line, or `None` if it isn't a trailer
+pub(crate) fn split_trailer(line: &str) -> Option<(&str, &str)> {
+    let (token, value) = line.split_once(':')?;
+    let value = value.trim();
+    let valid_token = !token.is_empty() && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    (valid_token && !value.is_empty()).then_some((token, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    #[test]
+    fn test_parse_trailers_skips_comments_and_blank_lines() {
+        let source = "# Team trailers\nReviewed-by: Jane Doe <jane@example.com>\n\nSigned-off-by:  CI Bot  \n";
+        assert_eq!(
+            parse_trailers(source).unwrap(),
+            vec!["Reviewed-by: Jane Doe <jane@example.com>", "Signed-off-by: CI Bot"]
+        );
+    }
+
+    #[test_case("Reviewed by: Jane", 1; "space in token")]
+    #[test_case("Reviewed-by Jane", 1; "missing colon")]
+    #[test_case("Acked-by: x\nReviewed-by:", 2; "empty value")]
+    #[test_case(": Jane", 1; "empty token")]
+    fn test_parse_trailers_rejects_malformed_lines(source: &str, expected_line: usize) {
+        match parse_trailers(source) {
+            Err(Error::InvalidTrailer { line, .. }) => assert_eq!(line, expected_line),
+            other => panic!("expected an invalid trailer error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_assemble_trailers_canonical_order() {
+        let parts: Vec<String> = [
+            "Signed-off-by: Jane Doe <jane@example.com>",
+            "Co-authored-by: John Roe <john@example.com>",
+            "Reviewed-by: Ann Poe",
+            "Refs: #7",
+            "Closes #12",
+            "Co-authored-by: Max Moe <max@example.com>",
+        ]
+        .map(String::from)
+        .to_vec();
+        assert_eq!(
+            format!("feat: add login\n\n- add form{}", assemble_trailers(&parts)),
+            "feat: add login\n\n- add form\n\n\
+            Refs: #7\n\
+            Closes #12\n\
+            Reviewed-by: Ann Poe\n\
+            Co-authored-by: John Roe <john@example.com>\n\
+            Co-authored-by: Max Moe <max@example.com>\n\
+            Signed-off-by: Jane Doe <jane@example.com>"
+        );
+    }
+
+    #[test_case(&[], ""; "no trailers")]
+    #[test_case(&["  ", "Signed-off-by: CI Bot  "], "\n\nSigned-off-by: CI Bot"; "blank and padded")]
+    #[test_case(&["BREAKING CHANGE: drops v1", "Fixes #3"], "\n\nFixes #3\nBREAKING CHANGE: drops v1"; "refs before breaking change")]
+    fn test_assemble_trailers_spacing(parts: &[&str], expected: &str) {
+        let parts: Vec<String> = parts.iter().map(|part| part.to_string()).collect();
+        assert_eq!(assemble_trailers(&parts), expected);
+    }
+
+    #[test]
+    fn test_has_trailer() {
+        let message = "feat: add login\n\nreviewed-by: Jane Doe";
+        assert!(has_trailer(message, "Reviewed-by: Jane Doe"));
+        assert!(!has_trailer(message, "Reviewed-by: John Doe"));
+    }
+}
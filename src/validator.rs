@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+use regex::Regex;
+use crate::{CommitConfig, Result, is_imperative_mood, parse_conventional};
+use crate::conventional::{breaking_change_footer, structure_violations};
+
+/// A single commit message rule. Validators run as a chain, see [`validators`].
+pub trait CommitValidator: Send + Sync {
+    /// Checks a commit message, returning the problem found if it breaks the rule
+    fn validate(&self, message: &str) -> std::result::Result<(), String>;
+}
+
+/// The subject follows `type(scope): description` with one of the allowed types
+pub struct ConventionalValidator {
+    pub allowed_types: Vec<String>,
+}
+
+impl CommitValidator for ConventionalValidator {
+    fn validate(&self, message: &str) -> std::result::Result<(), String> {
+        let Some(parsed) = parse_conventional(message) else {
+            return Err("subject does not follow the `type(scope): description` format".to_string());
+        };
+        if !self.allowed_types.contains(&parsed.commit_type) {
+            return Err(format!(
+                "type '{}' is not one of: {}",
+                parsed.commit_type,
+                self.allowed_types.join(", ")
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The scope, if any, is one of the allowed scopes (an empty list allows any scope).
+/// With a `scope_separator`, each part of a nested scope like `api/auth` is checked and
+/// none may be empty.
+pub struct ScopeValidator {
+    pub allowed_scopes: Vec<String>,
+    pub scope_separator: Option<String>,
+}
+
+impl CommitValidator for ScopeValidator {
+    fn validate(&self, message: &str) -> std::result::Result<(), String> {
+        let Some(parsed) = parse_conventional(message) else {
+            return Ok(());
+        };
+        for part in parsed.scope_parts(self.scope_separator.as_deref()) {
+            if part.trim().is_empty() {
+                return Err(format!("scope '{}' has an empty part", parsed.scope.as_deref().unwrap_or_default()));
+            }
+            if !self.allowed_scopes.is_empty() && !self.allowed_scopes.iter().any(|s| s == part) {
+                return Err(format!("scope '{}' is not one of: {}", part, self.allowed_scopes.join(", ")));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The subject line is at most `max_length` characters long, or the limit set for its type
+/// in `max_length_by_type`
+pub struct LengthValidator {
+    pub max_length: usize,
+    pub max_length_by_type: HashMap<String, usize>,
+}
+
+impl CommitValidator for LengthValidator {
+    fn validate(&self, message: &str) -> std::result::Result<(), String> {
+        let length = subject(message).chars().count();
+        let max_length = parse_conventional(message)
+            .and_then(|parsed| self.max_length_by_type.get(&parsed.commit_type).copied())
+            .unwrap_or(self.max_length);
+        if length > max_length {
+            return Err(format!("subject is {} characters long, the maximum is {}", length, max_length));
+        }
+        Ok(())
+    }
+}
+
+/// The subject line is at most `recommended_length` characters long, as git convention
+/// recommends. Only part of the chain with `strict_length`, see `length_warning`.
+pub struct RecommendedLengthValidator {
+    pub recommended_length: usize,
+}
+
+impl CommitValidator for RecommendedLengthValidator {
+    fn validate(&self, message: &str) -> std::result::Result<(), String> {
+        let length = subject(message).chars().count();
+        if length > self.recommended_length {
+            return Err(format!(
+                "subject is {} characters long, git convention recommends at most {}",
+                length, self.recommended_length
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The non-fatal warning for a subject over `recommended_length`, unless `strict_length`
+/// already makes it a validation failure
+pub fn length_warning(message: &str, config: &CommitConfig) -> Option<String> {
+    if config.strict_length {
+        return None;
+    }
+    RecommendedLengthValidator { recommended_length: config.recommended_length }
+        .validate(message)
+        .err()
+}
+
+/// The description starts with an imperative verb
+pub struct ImperativeValidator;
+
+impl CommitValidator for ImperativeValidator {
+    fn validate(&self, message: &str) -> std::result::Result<(), String> {
+        if !is_imperative_mood(subject(message)) {
+            return Err("description is not in imperative mood".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A `BREAKING CHANGE:` footer has a description, and with `require_footer` a breaking
+/// change marked with `!` has such a footer
+pub struct BreakingChangeValidator {
+    pub require_footer: bool,
+}
+
+impl CommitValidator for BreakingChangeValidator {
+    fn validate(&self, message: &str) -> std::result::Result<(), String> {
+        let breaking = parse_conventional(message).is_some_and(|parsed| parsed.breaking);
+        match breaking_change_footer(message) {
+            Some("") => Err("`BREAKING CHANGE:` footer has no description".to_string()),
+            None if breaking && self.require_footer => Err("breaking change `!` needs a `BREAKING CHANGE: <description>` footer".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The whole message matches a team-specific pattern, e.g. a required ticket reference
+pub struct RegexValidator {
+    pub regex: Regex,
+}
+
+impl CommitValidator for RegexValidator {
+    fn validate(&self, message: &str) -> std::result::Result<(), String> {
+        if !self.regex.is_match(message) {
+            return Err(format!("message does not match the pattern `{}`", self.regex.as_str()));
+        }
+        Ok(())
+    }
+}
+
+/// The whole message is laid out as the spec asks, see `structure_violations`
+pub struct StructureValidator;
+
+impl CommitValidator for StructureValidator {
+    fn validate(&self, message: &str) -> std::result::Result<(), String> {
+        let violations = structure_violations(message);
+        if violations.is_empty() {
+            return Ok(());
+        }
+        Err(violations.iter().map(ToString::to_string).collect::<Vec<_>>().join("; "))
+    }
+}
+
+/// Builds the validator chain for a configuration: conventional format, scope, length,
+/// breaking change footer and message layout, then the recommended length, imperative mood
+/// and the custom pattern when configured
+pub fn validators(config: &CommitConfig) -> Result<Vec<Box<dyn CommitValidator>>> {
+    let mut chain: Vec<Box<dyn CommitValidator>> = vec![
+        Box::new(ConventionalValidator { allowed_types: config.allowed_types.clone() }),
+        Box::new(ScopeValidator {
+            allowed_scopes: config.allowed_scopes.clone(),
+            scope_separator: config.scope_separator.clone(),
+        }),
+        Box::new(LengthValidator {
+            max_length: config.max_length,
+            max_length_by_type: config.max_length_by_type.clone(),
+        }),
+        Box::new(BreakingChangeValidator { require_footer: config.require_breaking_footer }),
+        Box::new(StructureValidator),
+    ];
+    if config.strict_length {
+        chain.push(Box::new(RecommendedLengthValidator { recommended_length: config.recommended_length }));
+    }
+    if config.enforce_imperative {
+        chain.push(Box::new(ImperativeValidator));
+    }
+    if let Some(pattern) = &config.custom_regex {
+        chain.push(Box::new(RegexValidator { regex: Regex::new(pattern)? }));
+    }
+    Ok(chain)
+}
+
+/// Runs every validator and collects the problems found; empty means the message is valid
+pub fn run_validators(validators: &[Box<dyn CommitValidator>], message: &str) -> Vec<String> {
+    validators
+        .iter()
+        .filter_map(|validator| validator.validate(message).err())
+        .collect()
+}
+
+fn subject(message: &str) -> &str {
+    message.lines().next().unwrap_or("").trim()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_nested_scopes_with_separator() {
+        let nested = CommitConfig {
+            allowed_scopes: vec!["api".to_string(), "auth".to_string()],
+            scope_separator: Some("/".to_string()),
+            ..CommitConfig::default()
+        };
+        let chain = validators(&nested).unwrap();
+        assert!(run_validators(&chain, "feat(api/auth): x").is_empty());
+        assert_eq!(run_validators(&chain, "feat(api/ui): x"), vec!["scope 'ui' is not one of: api, auth"]);
+        assert_eq!(run_validators(&chain, "feat(api/): x"), vec!["scope 'api/' has an empty part"]);
+
+        // Without a separator the scope is checked as a whole
+        let flat = CommitConfig { scope_separator: None, ..nested };
+        assert_eq!(
+            run_validators(&validators(&flat).unwrap(), "feat(api/auth): x"),
+            vec!["scope 'api/auth' is not one of: api, auth"]
+        );
+        assert!(run_validators(&validators(&CommitConfig::default()).unwrap(), "feat(api/auth): x").is_empty());
+    }
+
+    #[test]
+    fn test_custom_regex_requires_ticket() {
+        let config = CommitConfig {
+            custom_regex: Some(r"\b[A-Z]+-\d+\b".to_string()),
+            ..CommitConfig::default()
+        };
+        let chain = validators(&config).unwrap();
+        assert!(run_validators(&chain, "feat: add login\n\nRefs PROJ-12").is_empty());
+        assert_eq!(
+            run_validators(&chain, "feat: add login"),
+            vec![r"message does not match the pattern `\b[A-Z]+-\d+\b`"]
+        );
+    }
+
+    #[test]
+    fn test_breaking_change_requires_footer() {
+        let config = CommitConfig { require_breaking_footer: true, ..CommitConfig::default() };
+        let chain = validators(&config).unwrap();
+        let full = "feat(api)!: drop v1 endpoints\n\nRemove the deprecated routes.\n\n\
+            BREAKING CHANGE: clients must use /v2";
+        assert!(run_validators(&chain, full).is_empty());
+        assert_eq!(run_validators(&chain, "feat(api)!: drop v1 endpoints").len(), 1);
+        assert_eq!(run_validators(&chain, "feat!: drop v1\n\nBREAKING CHANGE: ").len(), 1);
+    }
+
+    #[test]
+    fn test_breaking_change_footer_is_optional_by_default() {
+        let chain = validators(&CommitConfig::default()).unwrap();
+        assert!(run_validators(&chain, "feat(api)!: drop v1 endpoints").is_empty());
+        assert_eq!(run_validators(&chain, "feat!: drop v1\n\nBREAKING CHANGE: ").len(), 1);
+    }
+
+    #[test]
+    fn test_invalid_custom_regex_is_an_error() {
+        let config = CommitConfig {
+            custom_regex: Some("(".to_string()),
+            ..CommitConfig::default()
+        };
+        assert!(validators(&config).is_err());
+    }
+
+    #[test]
+    fn test_max_length_by_type() {
+        let config = CommitConfig {
+            max_length: 30,
+            max_length_by_type: HashMap::from([("docs".to_string(), 50)]),
+            ..CommitConfig::default()
+        };
+        let chain = validators(&config).unwrap();
+        assert!(run_validators(&chain, "docs: explain the retry and backoff policy").is_empty());
+        assert_eq!(
+            run_validators(&chain, "feat: explain the retry and backoff policy"),
+            vec!["subject is 42 characters long, the maximum is 30"]
+        );
+    }
+
+    #[test]
+    fn test_recommended_length_only_warns() {
+        let message = "feat: explain the retry and backoff policy in the guide";
+        let config = CommitConfig::default();
+        assert!(run_validators(&validators(&config).unwrap(), message).is_empty());
+        assert_eq!(
+            length_warning(message, &config).as_deref(),
+            Some("subject is 55 characters long, git convention recommends at most 50")
+        );
+        assert_eq!(length_warning("feat: add retries", &config), None);
+    }
+
+    #[test]
+    fn test_strict_length_fails_validation() {
+        let message = "feat: explain the retry and backoff policy in the guide";
+        let config = CommitConfig { strict_length: true, ..CommitConfig::default() };
+        assert_eq!(
+            run_validators(&validators(&config).unwrap(), message),
+            vec!["subject is 55 characters long, git convention recommends at most 50"]
+        );
+        assert_eq!(length_warning(message, &config), None);
+    }
+}